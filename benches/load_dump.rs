@@ -0,0 +1,73 @@
+use criterion::{BatchSize, Criterion, Throughput, black_box, criterion_group, criterion_main};
+use num_bigint::BigInt;
+use python_marshal::{Object, PyReader, dump_bytes, load_bytes, magic::PyVersion};
+
+/// 60 functions with a nested function, a comprehension and string constants, marshaled by CPython 3.12
+const MODULE_312: &[u8] = include_bytes!("data/module_312.bin");
+
+fn inputs() -> Vec<(&'static str, Vec<u8>, PyVersion)> {
+    let version = PyVersion::new(3, 12);
+
+    let big_int = Object::Long(BigInt::from(7).pow(10_000));
+    let large_tuple = Object::Tuple((0..100_000).map(|i| Object::Long(i.into())).collect());
+
+    vec![
+        (
+            "big_int",
+            dump_bytes(big_int, None, version, 4).unwrap(),
+            version,
+        ),
+        (
+            "large_tuple",
+            dump_bytes(large_tuple, None, version, 4).unwrap(),
+            version,
+        ),
+        ("module", MODULE_312.to_vec(), version),
+    ]
+}
+
+fn bench_load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("load");
+
+    for (name, data, version) in inputs() {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+
+        group.bench_function(name, |b| {
+            b.iter(|| load_bytes(black_box(&data), version).unwrap())
+        });
+
+        // Reading only, without the `load_bytes` wrapper
+        group.bench_function(format!("{}_reader", name), |b| {
+            b.iter(|| {
+                PyReader::from_slice(black_box(&data), version)
+                    .read_object()
+                    .unwrap()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_dump(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dump");
+
+    for (name, data, version) in inputs() {
+        let (object, references) = load_bytes(&data, version).unwrap();
+
+        group.throughput(Throughput::Bytes(data.len() as u64));
+
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || (object.clone(), references.clone()),
+                |(object, references)| dump_bytes(object, Some(references), version, 4).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_load, bench_dump);
+criterion_main!(benches);
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use python_marshal::fuzz::{VERSIONS, reproduce};
+
+fuzz_target!(|data: &[u8]| {
+    for version in VERSIONS {
+        reproduce(data, version.into());
+    }
+});
@@ -1,7 +1,192 @@
 use crate::{
-    Code, CodeFlags, Error, Object, extract_object, extract_strings_tuple, resolve_object_ref,
+    Code, CodeFlags, Error, LocalKind, Object, ObjectHashable, PyString,
+    exceptiontable::{ExceptionEntry, decode_exceptiontable, handler_for_offset},
+    extract_strings_tuple,
+    magic::PyVersion,
+    optimizer, resolve_object_ref, resolver, siphash,
 };
 
+/// Checks that a code object field resolves to the expected variant, returning `Error::InvalidCodeField` otherwise.
+macro_rules! check_field {
+    ($field:expr, $name:literal, $variant:pat, $expected:literal, $refs:expr, $lazy:expr) => {
+        match resolve_object_ref!(Some((*$field).clone()), $refs)? {
+            // Unreadable, see `load_bytes_tolerant`
+            $variant | Object::Unknown { .. } => {}
+            // Not decoded yet, see `load_bytes_lazy`
+            Object::Raw { .. } if $lazy => {}
+            _ => {
+                return Err(Error::InvalidCodeField {
+                    field: $name,
+                    expected: $expected,
+                });
+            }
+        }
+    };
+}
+
+/// Checks that a code object field resolves to a tuple of strings, returning `Error::InvalidCodeField` otherwise.
+macro_rules! check_strings_field {
+    ($field:expr, $name:literal, $refs:expr, $lazy:expr) => {
+        let error = Error::InvalidCodeField {
+            field: $name,
+            expected: "tuple of strings",
+        };
+
+        match resolve_object_ref!(Some((*$field).clone()), $refs)? {
+            Object::Tuple(objs) => {
+                for obj in objs {
+                    match resolve_object_ref!(Some(obj), $refs)? {
+                        Object::String(_) | Object::Unknown { .. } => {}
+                        // Placeholders for references that aren't decoded yet, see `load_bytes_lazy`
+                        Object::Raw { .. } if $lazy => {}
+                        _ => return Err(error),
+                    }
+                }
+            }
+            Object::Unknown { .. } => {}
+            Object::Raw { .. } if $lazy => {}
+            _ => return Err(error),
+        }
+    };
+}
+
+/// Represents a Python code object for Python 3.0 up to and including 3.7, which all share the same layout.
+#[rustfmt::skip]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Code30 {
+    pub argcount:        u32,
+    pub kwonlyargcount:  u32,
+    pub nlocals:         u32,
+    pub stacksize:       u32,
+    pub flags:           CodeFlags,
+    pub code:            Box<Object>, // Needs to contain Vec<u8> as a value or a reference
+    pub consts:          Box<Object>, // Needs to contain Vec<Object> as a value or a reference
+    pub names:           Box<Object>, // Needs to contain Vec<PyString> as a value or a reference
+    pub varnames:        Box<Object>, // Needs to contain Vec<PyString> as a value or a reference
+    pub freevars:        Box<Object>, // Needs to contain Vec<PyString> as a value or a reference
+    pub cellvars:        Box<Object>, // Needs to contain Vec<PyString> as a value or a reference
+    pub filename:        Box<Object>, // Needs to contain PyString as a value or a reference
+    pub name:            Box<Object>, // Needs to contain PyString as a value or a reference
+    pub firstlineno:     u32,
+    pub lnotab:          Box<Object>, // Needs to contain Vec<u8>, as a value or a reference
+}
+
+impl Code30 {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        argcount: u32,
+        kwonlyargcount: u32,
+        nlocals: u32,
+        stacksize: u32,
+        flags: CodeFlags,
+        code: Box<Object>,
+        consts: Box<Object>,
+        names: Box<Object>,
+        varnames: Box<Object>,
+        freevars: Box<Object>,
+        cellvars: Box<Object>,
+        filename: Box<Object>,
+        name: Box<Object>,
+        firstlineno: u32,
+        lnotab: Box<Object>,
+        references: &[Object],
+    ) -> Result<Self, Error> {
+        let code = Self {
+            argcount,
+            kwonlyargcount,
+            nlocals,
+            stacksize,
+            flags,
+            code,
+            consts,
+            names,
+            varnames,
+            freevars,
+            cellvars,
+            filename,
+            name,
+            firstlineno,
+            lnotab,
+        };
+
+        code.check_fields(references, false)?;
+
+        Ok(code)
+    }
+
+    /// Checks that every object field (or the reference it points to) has the right type, returning `Error::InvalidCodeField` otherwise.
+    /// `lazy` also accepts the `Object::Raw` placeholders of a lazy reader, see `PyReader::with_lazy_code`.
+    pub(crate) fn check_fields(&self, references: &[Object], lazy: bool) -> Result<(), Error> {
+        check_field!(
+            self.code,
+            "code",
+            Object::Bytes(_),
+            "bytes",
+            references,
+            lazy
+        );
+        check_field!(
+            self.consts,
+            "consts",
+            Object::Tuple(_),
+            "tuple",
+            references,
+            lazy
+        );
+        check_strings_field!(self.names, "names", references, lazy);
+        check_strings_field!(self.varnames, "varnames", references, lazy);
+        check_strings_field!(self.freevars, "freevars", references, lazy);
+        check_strings_field!(self.cellvars, "cellvars", references, lazy);
+
+        check_field!(
+            self.filename,
+            "filename",
+            Object::String(_),
+            "string",
+            references,
+            lazy
+        );
+        check_field!(
+            self.name,
+            "name",
+            Object::String(_),
+            "string",
+            references,
+            lazy
+        );
+        check_field!(
+            self.lnotab,
+            "lnotab",
+            Object::Bytes(_),
+            "bytes",
+            references,
+            lazy
+        );
+
+        Ok(())
+    }
+
+    /// Returns the bytecode, resolving the reference if `code` is one.
+    pub fn code_bytes<'a>(&'a self, references: &'a [Object]) -> Result<&'a [u8], Error> {
+        bytes_field(&self.code, "code", references)
+    }
+
+    /// Returns the line number table, resolving the reference if `lnotab` is one.
+    pub fn lnotab_bytes<'a>(&'a self, references: &'a [Object]) -> Result<&'a [u8], Error> {
+        bytes_field(&self.lnotab, "lnotab", references)
+    }
+}
+
+impl std::fmt::Display for Code30 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<code object {}, file \"{}\", line {}>",
+            self.name, self.filename, self.firstlineno
+        )
+    }
+}
+
 /// Represents a Python code object for Python 3.10.
 #[rustfmt::skip]
 #[derive(Clone, Debug, PartialEq)]
@@ -25,6 +210,7 @@ pub struct Code310 {
 }
 
 impl Code310 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         argcount: u32,
         posonlyargcount: u32,
@@ -44,31 +230,7 @@ impl Code310 {
         linetable: Box<Object>,
         references: &[Object],
     ) -> Result<Self, Error> {
-        // Ensure all corresponding values are of the correct type
-        extract_object!(Some(resolve_object_ref!(Some((*code).clone()), references)?), Object::Bytes(bytes) => bytes, Error::NullInTuple)?;
-        extract_object!(Some(resolve_object_ref!(Some((*consts).clone()), references)?), Object::Tuple(objs) => objs, Error::NullInTuple)?;
-        extract_strings_tuple!(
-            extract_object!(Some(resolve_object_ref!(Some((*names).clone()), references)?), Object::Tuple(objs) => objs, Error::NullInTuple)?,
-            references
-        )?;
-        extract_strings_tuple!(
-            extract_object!(Some(resolve_object_ref!(Some((*varnames).clone()), references)?), Object::Tuple(objs) => objs, Error::NullInTuple)?,
-            references
-        )?;
-        extract_strings_tuple!(
-            extract_object!(Some(resolve_object_ref!(Some((*freevars).clone()), references)?), Object::Tuple(objs) => objs, Error::NullInTuple)?,
-            references
-        )?;
-        extract_strings_tuple!(
-            extract_object!(Some(resolve_object_ref!(Some((*cellvars).clone()), references)?), Object::Tuple(objs) => objs, Error::NullInTuple)?,
-            references
-        )?;
-
-        extract_object!(Some(resolve_object_ref!(Some((*filename).clone()), references)?), Object::String(string) => string, Error::UnexpectedObject)?;
-        extract_object!(Some(resolve_object_ref!(Some((*name).clone()), references)?), Object::String(string) => string, Error::UnexpectedObject)?;
-        extract_object!(Some(resolve_object_ref!(Some((*linetable).clone()), references)?), Object::Bytes(bytes) => bytes, Error::NullInTuple)?;
-
-        Ok(Self {
+        let code = Self {
             argcount,
             posonlyargcount,
             kwonlyargcount,
@@ -85,7 +247,83 @@ impl Code310 {
             name,
             firstlineno,
             linetable,
-        })
+        };
+
+        code.check_fields(references, false)?;
+
+        Ok(code)
+    }
+
+    /// Same as `Code30::check_fields`, and checks that `nlocals` matches the length of `varnames`.
+    pub(crate) fn check_fields(&self, references: &[Object], lazy: bool) -> Result<(), Error> {
+        check_field!(
+            self.code,
+            "code",
+            Object::Bytes(_),
+            "bytes",
+            references,
+            lazy
+        );
+        check_field!(
+            self.consts,
+            "consts",
+            Object::Tuple(_),
+            "tuple",
+            references,
+            lazy
+        );
+        check_strings_field!(self.names, "names", references, lazy);
+        check_strings_field!(self.varnames, "varnames", references, lazy);
+        check_strings_field!(self.freevars, "freevars", references, lazy);
+        check_strings_field!(self.cellvars, "cellvars", references, lazy);
+
+        // CPython rejects this as well (`co_nlocals != len(co_varnames)`), the locals are stored in an array of `nlocals` items
+        if let Object::Tuple(names) =
+            resolve_object_ref!(Some((*self.varnames).clone()), references)?
+            && names.len() != self.nlocals as usize
+        {
+            return Err(Error::InvalidCodeField {
+                field: "nlocals",
+                expected: "the length of varnames",
+            });
+        }
+
+        check_field!(
+            self.filename,
+            "filename",
+            Object::String(_),
+            "string",
+            references,
+            lazy
+        );
+        check_field!(
+            self.name,
+            "name",
+            Object::String(_),
+            "string",
+            references,
+            lazy
+        );
+        check_field!(
+            self.linetable,
+            "linetable",
+            Object::Bytes(_),
+            "bytes",
+            references,
+            lazy
+        );
+
+        Ok(())
+    }
+
+    /// Returns the bytecode, resolving the reference if `code` is one.
+    pub fn code_bytes<'a>(&'a self, references: &'a [Object]) -> Result<&'a [u8], Error> {
+        bytes_field(&self.code, "code", references)
+    }
+
+    /// Returns the line number table (the 3.10 replacement of `lnotab`), resolving the reference if `linetable` is one.
+    pub fn linetable_bytes<'a>(&'a self, references: &'a [Object]) -> Result<&'a [u8], Error> {
+        bytes_field(&self.linetable, "linetable", references)
     }
 }
 
@@ -100,6 +338,7 @@ impl std::fmt::Display for Code310 {
 }
 
 // Macro to generate Code31x structs for Python 3.11, 3.12, 3.13 (they share the same structure)
+// The fields are in the order `w_code` in CPython's Python/marshal.c writes them. Column information is part of `linetable`, there is no separate column table.
 macro_rules! define_code31x {
     ($($ver:ident),+) => {
         $(
@@ -125,6 +364,9 @@ macro_rules! define_code31x {
             }
 
             impl $ver {
+                /// Creates the code object, checking that every object field (or the reference it points to) has the right type.
+                /// `qualname` was added in Python 3.11 and is mandatory, a missing one (e.g. `Object::None` when converting a 3.10 code object)
+                /// fails with `Error::InvalidCodeField { field: "qualname", .. }`. Use `with_qualname_from_name` if there is no qualified name.
                 #[allow(clippy::too_many_arguments)]
                 pub fn new(
                     argcount: u32,
@@ -145,24 +387,69 @@ macro_rules! define_code31x {
                     exceptiontable: Box<Object>,
                     references: &[Object],
                 ) -> Result<Self, Error> {
-                    extract_object!(Some(resolve_object_ref!(Some((*code).clone()), references)?), Object::Bytes(bytes) => bytes, Error::NullInTuple)?;
-                    extract_object!(Some(resolve_object_ref!(Some((*consts).clone()), references)?), Object::Tuple(objs) => objs, Error::NullInTuple)?;
-                    extract_strings_tuple!(
-                        extract_object!(Some(resolve_object_ref!(Some((*names).clone()), references)?), Object::Tuple(objs) => objs, Error::NullInTuple)?,
-                        references
-                    )?;
-                    extract_strings_tuple!(
-                        extract_object!(Some(resolve_object_ref!(Some((*localsplusnames).clone()), references)?), Object::Tuple(objs) => objs, Error::NullInTuple)?,
-                        references
-                    )?;
-                    extract_object!(Some(resolve_object_ref!(Some((*localspluskinds).clone()), references)?), Object::Bytes(bytes) => bytes, Error::NullInTuple)?;
-                    extract_object!(Some(resolve_object_ref!(Some((*filename).clone()), references)?), Object::String(string) => string, Error::UnexpectedObject)?;
-                    extract_object!(Some(resolve_object_ref!(Some((*name).clone()), references)?), Object::String(string) => string, Error::UnexpectedObject)?;
-                    extract_object!(Some(resolve_object_ref!(Some((*qualname).clone()), references)?), Object::String(string) => string, Error::UnexpectedObject)?;
-                    extract_object!(Some(resolve_object_ref!(Some((*linetable).clone()), references)?), Object::Bytes(bytes) => bytes, Error::NullInTuple)?;
-                    extract_object!(Some(resolve_object_ref!(Some((*exceptiontable).clone()), references)?), Object::Bytes(bytes) => bytes, Error::NullInTuple)?;
-
-                    Ok(Self {
+                    let code = Self {
+                        argcount,
+                        posonlyargcount,
+                        kwonlyargcount,
+                        stacksize,
+                        flags,
+                        code,
+                        consts,
+                        names,
+                        localsplusnames,
+                        localspluskinds,
+                        filename,
+                        name,
+                        qualname,
+                        firstlineno,
+                        linetable,
+                        exceptiontable,
+                    };
+
+                    code.check_fields(references, false)?;
+
+                    Ok(code)
+                }
+
+                /// Same as `Code30::check_fields`.
+                pub(crate) fn check_fields(&self, references: &[Object], lazy: bool) -> Result<(), Error> {
+                    check_field!(self.code, "code", Object::Bytes(_), "bytes", references, lazy);
+                    check_field!(self.consts, "consts", Object::Tuple(_), "tuple", references, lazy);
+                    check_strings_field!(self.names, "names", references, lazy);
+                    check_strings_field!(self.localsplusnames, "localsplusnames", references, lazy);
+                    check_field!(self.localspluskinds, "localspluskinds", Object::Bytes(_), "bytes", references, lazy);
+                    check_field!(self.filename, "filename", Object::String(_), "string", references, lazy);
+                    check_field!(self.name, "name", Object::String(_), "string", references, lazy);
+                    check_field!(self.qualname, "qualname", Object::String(_), "string", references, lazy);
+                    check_field!(self.linetable, "linetable", Object::Bytes(_), "bytes", references, lazy);
+                    check_field!(self.exceptiontable, "exceptiontable", Object::Bytes(_), "bytes", references, lazy);
+
+                    Ok(())
+                }
+
+                /// Same as `new`, but defaults `qualname` to `name`.
+                #[allow(clippy::too_many_arguments)]
+                pub fn with_qualname_from_name(
+                    argcount: u32,
+                    posonlyargcount: u32,
+                    kwonlyargcount: u32,
+                    stacksize: u32,
+                    flags: CodeFlags,
+                    code: Box<Object>,
+                    consts: Box<Object>,
+                    names: Box<Object>,
+                    localsplusnames: Box<Object>,
+                    localspluskinds: Box<Object>,
+                    filename: Box<Object>,
+                    name: Box<Object>,
+                    firstlineno: u32,
+                    linetable: Box<Object>,
+                    exceptiontable: Box<Object>,
+                    references: &[Object],
+                ) -> Result<Self, Error> {
+                    let qualname = name.clone();
+
+                    Self::new(
                         argcount,
                         posonlyargcount,
                         kwonlyargcount,
@@ -179,7 +466,81 @@ macro_rules! define_code31x {
                         firstlineno,
                         linetable,
                         exceptiontable,
-                    })
+                        references,
+                    )
+                }
+
+                /// Returns the bytecode, resolving the reference if `code` is one.
+                pub fn code_bytes<'a>(&'a self, references: &'a [Object]) -> Result<&'a [u8], Error> {
+                    bytes_field(&self.code, "code", references)
+                }
+
+                /// Returns the location table, resolving the reference if `linetable` is one.
+                pub fn linetable_bytes<'a>(&'a self, references: &'a [Object]) -> Result<&'a [u8], Error> {
+                    bytes_field(&self.linetable, "linetable", references)
+                }
+
+                /// Returns the exception table, resolving the reference if `exceptiontable` is one.
+                pub fn exceptiontable_bytes<'a>(&'a self, references: &'a [Object]) -> Result<&'a [u8], Error> {
+                    bytes_field(&self.exceptiontable, "exceptiontable", references)
+                }
+
+                /// Decodes the exception table, resolving the reference if `exceptiontable` is one.
+                pub fn exception_entries(&self, references: &[Object]) -> Result<Vec<ExceptionEntry>, Error> {
+                    decode_exceptiontable(self.exceptiontable_bytes(references)?)
+                }
+
+                /// Returns the innermost exception handler whose `[start, end)` range covers the bytecode offset (in bytes).
+                pub fn handler_for_offset(
+                    &self,
+                    offset: u32,
+                    references: &[Object],
+                ) -> Result<Option<ExceptionEntry>, Error> {
+                    Ok(handler_for_offset(&self.exception_entries(references)?, offset))
+                }
+
+                /// Decodes `localspluskinds`, returning the kind of every name in `localsplusnames`. Unknown bits are kept.
+                pub fn localsplus_kinds(&self, references: &[Object]) -> Result<Vec<LocalKind>, Error> {
+                    Ok(bytes_field(&self.localspluskinds, "localspluskinds", references)?
+                        .iter()
+                        .map(|kind| LocalKind::from_bits_retain(*kind))
+                        .collect())
+                }
+
+                /// Reconstructs the `varnames`, `cellvars` and `freevars` of Python 3.10 and earlier from `localsplusnames` and `localspluskinds`,
+                /// the same way CPython computes `co_varnames`, `co_cellvars` and `co_freevars`.
+                /// Returns (varnames, cellvars, freevars)
+                pub fn split_locals(
+                    &self,
+                    references: &[Object],
+                ) -> Result<(Vec<PyString>, Vec<PyString>, Vec<PyString>), Error> {
+                    let names = strings_field(&self.localsplusnames, "localsplusnames", references)?;
+                    let kinds = self.localsplus_kinds(references)?;
+
+                    if names.len() != kinds.len() {
+                        return Err(Error::InvalidCodeField {
+                            field: "localspluskinds",
+                            expected: "one kind for every name in localsplusnames",
+                        });
+                    }
+
+                    let mut varnames = Vec::new();
+                    let mut cellvars = Vec::new();
+                    let mut freevars = Vec::new();
+
+                    for (name, kind) in names.into_iter().zip(kinds) {
+                        if kind.contains(LocalKind::LOCAL) {
+                            varnames.push(name.clone());
+                        }
+                        if kind.contains(LocalKind::CELL) {
+                            cellvars.push(name.clone());
+                        }
+                        if kind.contains(LocalKind::FREE) {
+                            freevars.push(name);
+                        }
+                    }
+
+                    Ok((varnames, cellvars, freevars))
                 }
             }
 
@@ -201,6 +562,7 @@ define_code31x!(Code311, Code312, Code313);
 impl std::fmt::Display for Code {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Code::V30(code) => write!(f, "{}", code),
             Code::V310(code) => write!(f, "{}", code),
             Code::V311(code) => write!(f, "{}", code),
             Code::V312(code) => write!(f, "{}", code),
@@ -208,3 +570,569 @@ impl std::fmt::Display for Code {
         }
     }
 }
+
+/// Resolves a field that should be a tuple of strings.
+fn strings_field(
+    field: &Object,
+    name: &'static str,
+    references: &[Object],
+) -> Result<Vec<PyString>, Error> {
+    let error = Error::InvalidCodeField {
+        field: name,
+        expected: "tuple of strings",
+    };
+
+    match resolve_object_ref!(Some(field.clone()), references)? {
+        Object::Tuple(objs) => extract_strings_tuple!(objs, references).map_err(|e| match e {
+            Error::UnexpectedObject => error,
+            e => e,
+        }),
+        _ => Err(error),
+    }
+}
+
+/// Borrows the bytes of a field that's either `Object::Bytes` or a reference to it.
+fn bytes_field<'a>(
+    field: &'a Object,
+    name: &'static str,
+    references: &'a [Object],
+) -> Result<&'a [u8], Error> {
+    let field = match field {
+        Object::LoadRef(index) | Object::StoreRef(index) => references
+            .get(*index)
+            .ok_or(Error::InvalidReference(*index))?,
+        field => field,
+    };
+
+    field.as_bytes().ok_or(Error::InvalidCodeField {
+        field: name,
+        expected: "bytes",
+    })
+}
+
+/// Borrows the string of a field that's either `Object::String` or a reference to it, `None` if it's something else.
+fn string_field<'a>(field: &'a Object, references: &'a [Object]) -> Option<&'a PyString> {
+    match field {
+        Object::LoadRef(index) | Object::StoreRef(index) => references.get(*index)?.as_string(),
+        field => field.as_string(),
+    }
+}
+
+/// Checks that every argument has a local variable name.
+fn validate_arguments(
+    flags: CodeFlags,
+    argcount: u32,
+    posonlyargcount: u32,
+    kwonlyargcount: u32,
+    locals: usize,
+    locals_field: &'static str,
+) -> Result<(), Error> {
+    if posonlyargcount > argcount {
+        return Err(Error::InvalidCodeField {
+            field: "posonlyargcount",
+            expected: "at most argcount",
+        });
+    }
+
+    let total_args = argcount as usize
+        + kwonlyargcount as usize
+        + flags.contains(CodeFlags::VARARGS) as usize
+        + flags.contains(CodeFlags::VARKEYWORDS) as usize;
+
+    if total_args > locals {
+        return Err(Error::InvalidCodeField {
+            field: locals_field,
+            expected: "a name for every argument",
+        });
+    }
+
+    Ok(())
+}
+
+/// Checks that no name is both a cell variable and a free variable.
+fn validate_cell_and_free_vars(
+    cellvars: &Object,
+    freevars: &Object,
+    references: &[Object],
+) -> Result<(), Error> {
+    let cellvars = strings_field(cellvars, "cellvars", references)?;
+    let freevars = strings_field(freevars, "freevars", references)?;
+
+    if freevars.iter().any(|name| cellvars.contains(name)) {
+        return Err(Error::InvalidCodeField {
+            field: "freevars",
+            expected: "no names shared with cellvars",
+        });
+    }
+
+    Ok(())
+}
+
+/// Writes the location independent fields of a Code31x object (Python 3.11, 3.12, 3.13) which share the same structure
+macro_rules! write_code311_content {
+    ($code:ident, $tag:literal, $out:ident) => {{
+        $out.push($tag);
+        for value in [
+            $code.argcount,
+            $code.posonlyargcount,
+            $code.kwonlyargcount,
+            $code.stacksize,
+            $code.flags.bits(),
+        ] {
+            $out.extend_from_slice(&value.to_le_bytes());
+        }
+        for obj in [
+            &$code.code,
+            &$code.consts,
+            &$code.names,
+            &$code.localsplusnames,
+            &$code.localspluskinds,
+            &$code.name,
+            &$code.qualname,
+            &$code.exceptiontable,
+        ] {
+            write_content(obj, $out);
+        }
+    }};
+}
+
+/// Writes an unambiguous encoding of `obj` for `Code::content_hash`, nested code objects only contribute their location independent fields.
+fn write_content(obj: &Object, out: &mut Vec<u8>) {
+    match obj {
+        Object::None => out.push(b'N'),
+        Object::StopIteration => out.push(b'S'),
+        Object::Ellipsis => out.push(b'.'),
+        Object::Bool(value) => out.extend_from_slice(&[b'B', *value as u8]),
+        Object::Long(value) => {
+            out.push(b'l');
+            write_len_prefixed(&value.to_signed_bytes_le(), out);
+        }
+        Object::Float(value) => {
+            out.push(b'g');
+            out.extend_from_slice(&value.to_bits().to_le_bytes());
+        }
+        Object::Complex(value) => {
+            out.push(b'y');
+            out.extend_from_slice(&value.re.to_bits().to_le_bytes());
+            out.extend_from_slice(&value.im.to_bits().to_le_bytes());
+        }
+        Object::Bytes(value) => {
+            out.push(b's');
+            write_len_prefixed(value, out);
+        }
+        Object::String(value) => {
+            out.push(b'u');
+            write_len_prefixed(&value.value, out);
+        }
+        Object::Tuple(objs) | Object::List(objs) => {
+            out.push(if matches!(obj, Object::Tuple(_)) {
+                b'('
+            } else {
+                b'['
+            });
+            out.extend_from_slice(&(objs.len() as u64).to_le_bytes());
+            for obj in objs {
+                write_content(obj, out);
+            }
+        }
+        Object::Dict(dict) => {
+            out.push(b'{');
+            out.extend_from_slice(&(dict.len() as u64).to_le_bytes());
+            for (key, value) in dict {
+                write_hashable_content(key, out);
+                write_content(value, out);
+            }
+        }
+        Object::Set(set) | Object::FrozenSet(set) => {
+            out.push(if matches!(obj, Object::Set(_)) {
+                b'<'
+            } else {
+                b'>'
+            });
+            out.extend_from_slice(&(set.len() as u64).to_le_bytes());
+            for obj in set {
+                write_hashable_content(obj, out);
+            }
+        }
+        Object::Code(code) => match code {
+            Code::V30(code) => {
+                out.push(b'0');
+                for value in [
+                    code.argcount,
+                    code.kwonlyargcount,
+                    code.nlocals,
+                    code.stacksize,
+                    code.flags.bits(),
+                ] {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+                for obj in [
+                    &code.code,
+                    &code.consts,
+                    &code.names,
+                    &code.varnames,
+                    &code.freevars,
+                    &code.cellvars,
+                    &code.name,
+                ] {
+                    write_content(obj, out);
+                }
+            }
+            Code::V310(code) => {
+                out.push(b'1');
+                for value in [
+                    code.argcount,
+                    code.posonlyargcount,
+                    code.kwonlyargcount,
+                    code.nlocals,
+                    code.stacksize,
+                    code.flags.bits(),
+                ] {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+                for obj in [
+                    &code.code,
+                    &code.consts,
+                    &code.names,
+                    &code.varnames,
+                    &code.freevars,
+                    &code.cellvars,
+                    &code.name,
+                ] {
+                    write_content(obj, out);
+                }
+            }
+            Code::V311(code) => write_code311_content!(code, b'2', out),
+            Code::V312(code) => write_code311_content!(code, b'3', out),
+            Code::V313(code) => write_code311_content!(code, b'4', out),
+        },
+        // Recursive references are left in place by `resolve_all_refs`
+        Object::LoadRef(index) | Object::StoreRef(index) => {
+            out.push(if matches!(obj, Object::LoadRef(_)) {
+                b'r'
+            } else {
+                b'&'
+            });
+            out.extend_from_slice(&(*index as u64).to_le_bytes());
+        }
+        Object::Raw { start, len } => {
+            out.push(b'?');
+            out.extend_from_slice(&(*start as u64).to_le_bytes());
+            out.extend_from_slice(&(*len as u64).to_le_bytes());
+        }
+        Object::Unknown { offset } => {
+            out.push(b'!');
+            out.extend_from_slice(&offset.to_le_bytes());
+        }
+    }
+}
+
+/// Same as `write_content` for dict keys and frozenset elements.
+fn write_hashable_content(obj: &ObjectHashable, out: &mut Vec<u8>) {
+    match obj {
+        ObjectHashable::Tuple(objs) => {
+            out.push(b'(');
+            out.extend_from_slice(&(objs.len() as u64).to_le_bytes());
+            for obj in objs {
+                write_hashable_content(obj, out);
+            }
+        }
+        ObjectHashable::FrozenSet(set) => {
+            // The set doesn't have a stable iteration order, so the encoded elements are sorted
+            let mut elements = set
+                .iter()
+                .map(|obj| {
+                    let mut element = Vec::new();
+                    write_hashable_content(obj, &mut element);
+                    element
+                })
+                .collect::<Vec<_>>();
+            elements.sort();
+
+            out.push(b'>');
+            out.extend_from_slice(&(elements.len() as u64).to_le_bytes());
+            for element in elements {
+                out.extend_from_slice(&element);
+            }
+        }
+        _ => write_content(&obj.clone().into_object(), out),
+    }
+}
+
+fn write_len_prefixed(data: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Validates the cross-field invariants of a Code31x object (Python 3.11, 3.12, 3.13) which share the same structure
+macro_rules! validate_code311 {
+    ($code:ident, $references:ident) => {{
+        let localsplusnames =
+            strings_field(&$code.localsplusnames, "localsplusnames", $references)?;
+
+        validate_arguments(
+            $code.flags,
+            $code.argcount,
+            $code.posonlyargcount,
+            $code.kwonlyargcount,
+            localsplusnames.len(),
+            "localsplusnames",
+        )?;
+
+        match resolve_object_ref!(Some((*$code.localspluskinds).clone()), $references)? {
+            Object::Bytes(kinds) if kinds.len() == localsplusnames.len() => Ok(()),
+            Object::Bytes(_) => Err(Error::InvalidCodeField {
+                field: "localspluskinds",
+                expected: "one kind for every name in localsplusnames",
+            }),
+            _ => Err(Error::InvalidCodeField {
+                field: "localspluskinds",
+                expected: "bytes",
+            }),
+        }
+    }};
+}
+
+impl Code {
+    /// Checks invariants between the fields of the code object that the constructors don't check, e.g. that `localspluskinds` has a kind for every name in `localsplusnames`.
+    /// A code object that fails these checks can crash the interpreter when it's loaded, so validate synthesized code objects before writing them.
+    pub fn validate(&self, references: &[Object]) -> Result<(), Error> {
+        match self {
+            Code::V30(code) => {
+                let varnames = strings_field(&code.varnames, "varnames", references)?;
+
+                validate_arguments(
+                    code.flags,
+                    code.argcount,
+                    0,
+                    code.kwonlyargcount,
+                    varnames.len(),
+                    "varnames",
+                )?;
+                validate_cell_and_free_vars(&code.cellvars, &code.freevars, references)
+            }
+            Code::V310(code) => {
+                let varnames = strings_field(&code.varnames, "varnames", references)?;
+
+                validate_arguments(
+                    code.flags,
+                    code.argcount,
+                    code.posonlyargcount,
+                    code.kwonlyargcount,
+                    varnames.len(),
+                    "varnames",
+                )?;
+                validate_cell_and_free_vars(&code.cellvars, &code.freevars, references)
+            }
+            Code::V311(code) => validate_code311!(code, references),
+            Code::V312(code) => validate_code311!(code, references),
+            Code::V313(code) => validate_code311!(code, references),
+        }
+    }
+
+    /// Replaces the constants of the code object. `references` is the table the code object is written with, e.g. the one
+    /// `extract_code` returns. It's renumbered afterwards, since the old constants might have stored references that are
+    /// loaded elsewhere. To change a code object nested in a module, extract it and put it back with `PycFile::replace_function`.
+    /// Returns `Error::InvalidObject` for a `Raw` or `Unknown` constant and `Error::InvalidReference` for a reference out of bounds.
+    pub fn set_consts(
+        &mut self,
+        consts: Vec<Object>,
+        references: &mut Vec<Object>,
+    ) -> Result<(), Error> {
+        let consts = Box::new(Object::Tuple(consts));
+
+        self.replace_field(&consts, references, |code| match code {
+            Code::V30(code) => code.consts = consts.clone(),
+            Code::V310(code) => code.consts = consts.clone(),
+            Code::V311(code) => code.consts = consts.clone(),
+            Code::V312(code) => code.consts = consts.clone(),
+            Code::V313(code) => code.consts = consts.clone(),
+        })
+    }
+
+    /// Replaces the names used by the bytecode of the code object, like `set_consts`. Every name has to be a string or a reference to one.
+    pub fn set_names(
+        &mut self,
+        names: Vec<Object>,
+        references: &mut Vec<Object>,
+    ) -> Result<(), Error> {
+        for name in &names {
+            let name = match name {
+                Object::LoadRef(index) | Object::StoreRef(index) => references
+                    .get(*index)
+                    .ok_or(Error::InvalidReference(*index))?,
+                name => name,
+            };
+
+            if !matches!(name, Object::String(_)) {
+                return Err(Error::InvalidCodeField {
+                    field: "names",
+                    expected: "tuple of strings",
+                });
+            }
+        }
+
+        let names = Box::new(Object::Tuple(names));
+
+        self.replace_field(&names, references, |code| match code {
+            Code::V30(code) => code.names = names.clone(),
+            Code::V310(code) => code.names = names.clone(),
+            Code::V311(code) => code.names = names.clone(),
+            Code::V312(code) => code.names = names.clone(),
+            Code::V313(code) => code.names = names.clone(),
+        })
+    }
+
+    /// Sets a field with `set` and renumbers the references, see `set_consts`. The code object is left unchanged on an error.
+    fn replace_field(
+        &mut self,
+        value: &Object,
+        references: &mut Vec<Object>,
+        set: impl FnOnce(&mut Code),
+    ) -> Result<(), Error> {
+        let mut invalid = None;
+        value.walk(&mut |obj| {
+            if invalid.is_none() && matches!(obj, Object::Raw { .. } | Object::Unknown { .. }) {
+                invalid = Some(obj.clone());
+            }
+        });
+
+        if let Some(obj) = invalid {
+            return Err(Error::InvalidObject(obj));
+        }
+
+        let mut code = self.clone();
+        set(&mut code);
+
+        let (object, new_references) =
+            optimizer::place_references(&Object::Code(code), references)?;
+        let Object::Code(code) = object else {
+            return Err(Error::UnexpectedObject);
+        };
+
+        *self = code;
+        *references = new_references;
+
+        Ok(())
+    }
+
+    /// Checks that the code object has the layout of `version` and doesn't use flags or local kinds that were added after it.
+    /// Returns `Error::InvalidObject` for the wrong layout and `Error::InvalidCodeField` for a flag or kind that's too new.
+    pub fn validate_version(&self, version: PyVersion, references: &[Object]) -> Result<(), Error> {
+        let (layout_matches, flags) = match self {
+            Code::V30(code) => (
+                matches!(
+                    version,
+                    PyVersion {
+                        major: 3,
+                        minor: 0..=7,
+                        ..
+                    }
+                ),
+                code.flags,
+            ),
+            Code::V310(code) => (version == (3, 10), code.flags),
+            Code::V311(code) => (version == (3, 11), code.flags),
+            Code::V312(code) => (version == (3, 12), code.flags),
+            Code::V313(code) => (version == (3, 13), code.flags),
+        };
+
+        if !layout_matches {
+            return Err(Error::InvalidObject(Object::Code(self.clone())));
+        }
+
+        // (flag, version it was added in)
+        let added = [
+            (CodeFlags::COROUTINE, (3, 5)),
+            (CodeFlags::ITERABLE_COROUTINE, (3, 5)),
+            (CodeFlags::ASYNC_GENERATOR, (3, 6)),
+            (CodeFlags::NO_MONITORING_EVENTS, (3, 13)),
+        ];
+
+        if added
+            .into_iter()
+            .any(|(flag, added_in)| flags.contains(flag) && version < added_in)
+        {
+            return Err(Error::InvalidCodeField {
+                field: "flags",
+                expected: "flags supported by the Python version",
+            });
+        }
+
+        if let Code::V311(code) = self
+            && code
+                .localsplus_kinds(references)?
+                .iter()
+                .any(|kind| kind.contains(LocalKind::HIDDEN))
+        {
+            return Err(Error::InvalidCodeField {
+                field: "localspluskinds",
+                expected: "kinds supported by Python 3.11",
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Hashes the parts of the code object that don't depend on where it's defined, to find the same function in different files.
+    /// References are resolved first, so an object stored as a reference hashes the same as one stored inline.
+    ///
+    /// Included: the layout (Python version range) of the code object, the argument counts, `nlocals` (up to 3.10), `stacksize`, `flags`,
+    /// the bytecode, `consts`, `names`, the local variable names (`varnames`, `freevars` and `cellvars`, or `localsplusnames` and
+    /// `localspluskinds` from 3.11), `name`, `qualname` and `exceptiontable` (3.11+).
+    /// Excluded: `filename`, `firstlineno` and the line number table (`lnotab` or `linetable`).
+    ///
+    /// Code objects in `consts` are hashed by the same rules, so nested functions don't affect the hash by their location either.
+    /// The string kind (e.g. interned or not) is ignored, only the value of a string counts.
+    /// The hash is stable across runs and platforms, lazily loaded objects (`Object::Raw`) have to be decoded first to be compared.
+    /// Returns `Error::InvalidReference` or `Error::RecursiveReference` if the references can't be resolved.
+    pub fn content_hash(&self, references: &[Object]) -> Result<u64, Error> {
+        let code = Object::Code(self.clone());
+        resolver::check_resolvable(&code, references)?;
+
+        let (resolved, _) = resolver::resolve_all_refs(&code, references);
+
+        let mut content = Vec::new();
+        write_content(&resolved, &mut content);
+
+        Ok(siphash::siphash(0, 0, &content, 2, 4))
+    }
+
+    /// Returns whether this is the code object of a module rather than of a function or class body, i.e. whether its name is `<module>`.
+    pub fn is_module_level(&self, references: &[Object]) -> bool {
+        string_field(self.name(), references).is_some_and(|name| name.value == "<module>")
+    }
+
+    /// Returns whether this is the code object of a package, i.e. a module compiled from an `__init__.py` file.
+    /// The code object doesn't store this itself, so it's derived from the `filename` the source was compiled with.
+    pub fn is_package_init(&self, references: &[Object]) -> bool {
+        self.is_module_level(references)
+            && string_field(self.filename(), references).is_some_and(|filename| {
+                filename
+                    .value
+                    .rsplit(|&b| b == b'/' || b == b'\\')
+                    .next()
+                    .is_some_and(|file| file == b"__init__.py")
+            })
+    }
+
+    fn name(&self) -> &Object {
+        match self {
+            Code::V30(code) => &code.name,
+            Code::V310(code) => &code.name,
+            Code::V311(code) => &code.name,
+            Code::V312(code) => &code.name,
+            Code::V313(code) => &code.name,
+        }
+    }
+
+    fn filename(&self) -> &Object {
+        match self {
+            Code::V30(code) => &code.filename,
+            Code::V310(code) => &code.filename,
+            Code::V311(code) => &code.filename,
+            Code::V312(code) => &code.filename,
+            Code::V313(code) => &code.filename,
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::borrow::Cow;
+
+use num_bigint::BigInt;
+
+use crate::{Code, Object};
+
+/// A single difference between two objects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObjectDiff {
+    /// Path to the differing object, e.g. `consts[2].names[0]`. Empty if the root objects differ.
+    pub path: String,
+    /// The value on the left side, `None` if it only exists on the right side.
+    pub left: Option<Object>,
+    /// The value on the right side, `None` if it only exists on the left side.
+    pub right: Option<Object>,
+}
+
+/// Collects the fields of a Code31x object (Python 3.11, 3.12, 3.13) which share the same structure
+macro_rules! code311_fields {
+    ($code:ident) => {
+        vec![
+            (
+                "argcount",
+                Cow::Owned(Object::Long(BigInt::from($code.argcount))),
+            ),
+            (
+                "posonlyargcount",
+                Cow::Owned(Object::Long(BigInt::from($code.posonlyargcount))),
+            ),
+            (
+                "kwonlyargcount",
+                Cow::Owned(Object::Long(BigInt::from($code.kwonlyargcount))),
+            ),
+            (
+                "stacksize",
+                Cow::Owned(Object::Long(BigInt::from($code.stacksize))),
+            ),
+            (
+                "flags",
+                Cow::Owned(Object::Long(BigInt::from($code.flags.bits()))),
+            ),
+            ("code", Cow::Borrowed(&*$code.code)),
+            ("consts", Cow::Borrowed(&*$code.consts)),
+            ("names", Cow::Borrowed(&*$code.names)),
+            ("localsplusnames", Cow::Borrowed(&*$code.localsplusnames)),
+            ("localspluskinds", Cow::Borrowed(&*$code.localspluskinds)),
+            ("filename", Cow::Borrowed(&*$code.filename)),
+            ("name", Cow::Borrowed(&*$code.name)),
+            ("qualname", Cow::Borrowed(&*$code.qualname)),
+            (
+                "firstlineno",
+                Cow::Owned(Object::Long(BigInt::from($code.firstlineno))),
+            ),
+            ("linetable", Cow::Borrowed(&*$code.linetable)),
+            ("exceptiontable", Cow::Borrowed(&*$code.exceptiontable)),
+        ]
+    };
+}
+
+/// Returns the fields of a code object in marshal order. Integer fields are represented as `Object::Long`.
+fn code_fields(code: &Code) -> Vec<(&'static str, Cow<'_, Object>)> {
+    match code {
+        Code::V30(code) => vec![
+            (
+                "argcount",
+                Cow::Owned(Object::Long(BigInt::from(code.argcount))),
+            ),
+            (
+                "kwonlyargcount",
+                Cow::Owned(Object::Long(BigInt::from(code.kwonlyargcount))),
+            ),
+            (
+                "nlocals",
+                Cow::Owned(Object::Long(BigInt::from(code.nlocals))),
+            ),
+            (
+                "stacksize",
+                Cow::Owned(Object::Long(BigInt::from(code.stacksize))),
+            ),
+            (
+                "flags",
+                Cow::Owned(Object::Long(BigInt::from(code.flags.bits()))),
+            ),
+            ("code", Cow::Borrowed(&*code.code)),
+            ("consts", Cow::Borrowed(&*code.consts)),
+            ("names", Cow::Borrowed(&*code.names)),
+            ("varnames", Cow::Borrowed(&*code.varnames)),
+            ("freevars", Cow::Borrowed(&*code.freevars)),
+            ("cellvars", Cow::Borrowed(&*code.cellvars)),
+            ("filename", Cow::Borrowed(&*code.filename)),
+            ("name", Cow::Borrowed(&*code.name)),
+            (
+                "firstlineno",
+                Cow::Owned(Object::Long(BigInt::from(code.firstlineno))),
+            ),
+            ("lnotab", Cow::Borrowed(&*code.lnotab)),
+        ],
+        Code::V310(code) => vec![
+            (
+                "argcount",
+                Cow::Owned(Object::Long(BigInt::from(code.argcount))),
+            ),
+            (
+                "posonlyargcount",
+                Cow::Owned(Object::Long(BigInt::from(code.posonlyargcount))),
+            ),
+            (
+                "kwonlyargcount",
+                Cow::Owned(Object::Long(BigInt::from(code.kwonlyargcount))),
+            ),
+            (
+                "nlocals",
+                Cow::Owned(Object::Long(BigInt::from(code.nlocals))),
+            ),
+            (
+                "stacksize",
+                Cow::Owned(Object::Long(BigInt::from(code.stacksize))),
+            ),
+            (
+                "flags",
+                Cow::Owned(Object::Long(BigInt::from(code.flags.bits()))),
+            ),
+            ("code", Cow::Borrowed(&*code.code)),
+            ("consts", Cow::Borrowed(&*code.consts)),
+            ("names", Cow::Borrowed(&*code.names)),
+            ("varnames", Cow::Borrowed(&*code.varnames)),
+            ("freevars", Cow::Borrowed(&*code.freevars)),
+            ("cellvars", Cow::Borrowed(&*code.cellvars)),
+            ("filename", Cow::Borrowed(&*code.filename)),
+            ("name", Cow::Borrowed(&*code.name)),
+            (
+                "firstlineno",
+                Cow::Owned(Object::Long(BigInt::from(code.firstlineno))),
+            ),
+            ("linetable", Cow::Borrowed(&*code.linetable)),
+        ],
+        Code::V311(code) => code311_fields!(code),
+        Code::V312(code) => code311_fields!(code),
+        Code::V313(code) => code311_fields!(code),
+    }
+}
+
+fn join_field(path: &str, field: &str) -> String {
+    if path.is_empty() {
+        field.to_string()
+    } else {
+        format!("{}.{}", path, field)
+    }
+}
+
+fn diff_sequences(path: &str, a: &[Object], b: &[Object], diffs: &mut Vec<ObjectDiff>) {
+    for i in 0..a.len().max(b.len()) {
+        let path = format!("{}[{}]", path, i);
+
+        match (a.get(i), b.get(i)) {
+            (Some(a), Some(b)) => diff_into(&path, a, b, diffs),
+            (a, b) => diffs.push(ObjectDiff {
+                path,
+                left: a.cloned(),
+                right: b.cloned(),
+            }),
+        }
+    }
+}
+
+fn diff_into(path: &str, a: &Object, b: &Object, diffs: &mut Vec<ObjectDiff>) {
+    match (a, b) {
+        (Object::Tuple(a), Object::Tuple(b)) | (Object::List(a), Object::List(b)) => {
+            diff_sequences(path, a, b, diffs);
+        }
+        (Object::Dict(a), Object::Dict(b)) => {
+            for (key, value) in a.iter() {
+                let path = format!("{}[{}]", path, key);
+
+                match b.get(key) {
+                    Some(other) => diff_into(&path, value, other, diffs),
+                    None => diffs.push(ObjectDiff {
+                        path,
+                        left: Some(value.clone()),
+                        right: None,
+                    }),
+                }
+            }
+
+            for (key, value) in b.iter().filter(|(key, _)| !a.contains_key(*key)) {
+                diffs.push(ObjectDiff {
+                    path: format!("{}[{}]", path, key),
+                    left: None,
+                    right: Some(value.clone()),
+                });
+            }
+        }
+        (Object::Code(code_a), Object::Code(code_b))
+            if std::mem::discriminant(code_a) == std::mem::discriminant(code_b) =>
+        {
+            for ((field, a), (_, b)) in code_fields(code_a).iter().zip(code_fields(code_b).iter()) {
+                diff_into(&join_field(path, field), a, b, diffs);
+            }
+        }
+        (a, b) if a != b => diffs.push(ObjectDiff {
+            path: path.to_string(),
+            left: Some(a.clone()),
+            right: Some(b.clone()),
+        }),
+        _ => {}
+    }
+}
+
+/// Compares two object trees and returns every difference along with its path, e.g. `consts[2].names[0]`.
+/// References are compared as is, use `resolve_all_refs` on both sides first to compare the logical objects.
+pub fn diff_objects(a: &Object, b: &Object) -> Vec<ObjectDiff> {
+    let mut diffs = Vec::new();
+
+    diff_into("", a, b, &mut diffs);
+
+    diffs
+}
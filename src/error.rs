@@ -30,9 +30,16 @@ pub enum Error {
     InvalidUtf16String(std::string::FromUtf16Error),
     InvalidReference(usize),
     InvalidStoreRef,
+    RecursiveReference,
     UnexpectedObject,
+    InvalidCodeField {
+        field: &'static str,
+        expected: &'static str,
+    },
     UnexpectedNull,
     DepthLimitExceeded,
+    TrailingData(usize),
+    TruncatedTable,
 }
 
 impl Display for Error {
@@ -75,9 +82,32 @@ impl Display for Error {
                 write!(f, "bad marshal data (invalid reference index: {})", index)
             }
             Error::InvalidStoreRef => write!(f, "bad marshal data (invalid store reference)"),
+            Error::RecursiveReference => {
+                write!(
+                    f,
+                    "object contains recursive references that can't be resolved"
+                )
+            }
             Error::DepthLimitExceeded => write!(f, "depth limit exceeded while processing object"),
             Error::UnexpectedObject => write!(f, "unexpected object"),
+            Error::InvalidCodeField { field, expected } => {
+                write!(
+                    f,
+                    "invalid code object field `{}`, expected {}",
+                    field, expected
+                )
+            }
             Error::UnexpectedNull => write!(f, "unexpected NULL object"),
+            Error::TrailingData(len) => {
+                write!(
+                    f,
+                    "bad marshal data ({} trailing bytes after the object)",
+                    len
+                )
+            }
+            Error::TruncatedTable => {
+                write!(f, "bad code object table (ends in the middle of an entry)")
+            }
         }
     }
 }
@@ -95,3 +125,45 @@ impl From<std::string::FromUtf16Error> for Error {
         Error::InvalidUtf16String(err)
     }
 }
+
+/// Returned by `verify_roundtrip` when a pyc file can't be loaded or doesn't dump to the exact same bytes.
+#[derive(Debug)]
+pub enum RoundtripError {
+    /// Loading or dumping the pyc file failed
+    Error(Error),
+    /// The dumped bytes differ from the original ones. A byte is `None` if that side ended before the offset.
+    Mismatch {
+        offset: usize,
+        original: Option<u8>,
+        original_kind: Kind,
+        dumped: Option<u8>,
+        dumped_kind: Kind,
+    },
+}
+
+impl Display for RoundtripError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            RoundtripError::Error(err) => write!(f, "{}", err),
+            RoundtripError::Mismatch {
+                offset,
+                original,
+                original_kind,
+                dumped,
+                dumped_kind,
+            } => write!(
+                f,
+                "bytearrays differ at index {}: {:?} ({:?}) != {:?} ({:?})",
+                offset, original, original_kind, dumped, dumped_kind
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RoundtripError {}
+
+impl From<Error> for RoundtripError {
+    fn from(err: Error) -> Self {
+        RoundtripError::Error(err)
+    }
+}
@@ -0,0 +1,69 @@
+//! Decoding of the exception table (`co_exceptiontable`) of Python 3.11+ code objects, see
+//! https://github.com/python/cpython/blob/main/InternalDocs/exception_handling.md#format-of-the-exception-table
+
+use crate::{error::Error, linetable::TableReader};
+
+const CODE_UNIT_SIZE: u32 = 2;
+
+/// A single entry of the exception table. Offsets are in bytes, the entry covers `[start, end)`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExceptionEntry {
+    pub start: u32,
+    pub end: u32,
+    /// Offset of the handler
+    pub target: u32,
+    /// Stack depth to pop to before jumping to the handler
+    pub depth: u32,
+    /// Whether the offset of the raising instruction is pushed before the exception
+    pub lasti: bool,
+}
+
+impl ExceptionEntry {
+    /// Whether the half-open range of the entry covers the offset
+    pub fn contains(&self, offset: u32) -> bool {
+        self.start <= offset && offset < self.end
+    }
+}
+
+/// Decodes an exception table into its entries, in the order they're stored.
+pub fn decode_exceptiontable(table: &[u8]) -> Result<Vec<ExceptionEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut reader = TableReader { table, position: 0 };
+
+    while reader.position < table.len() {
+        if table[reader.position] & 0x80 == 0 {
+            return Err(Error::InvalidConversion);
+        }
+
+        let start = reader.read_exception_varint()?;
+        let size = reader.read_exception_varint()?;
+        let target = reader.read_exception_varint()?;
+        let depth_lasti = reader.read_exception_varint()?;
+
+        let to_bytes = |units: u32| {
+            units
+                .checked_mul(CODE_UNIT_SIZE)
+                .ok_or(Error::InvalidConversion)
+        };
+
+        entries.push(ExceptionEntry {
+            start: to_bytes(start)?,
+            end: to_bytes(start.checked_add(size).ok_or(Error::InvalidConversion)?)?,
+            target: to_bytes(target)?,
+            depth: depth_lasti >> 1,
+            lasti: depth_lasti & 1 == 1,
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Returns the innermost entry covering the offset, which is the one with the smallest range.
+/// CPython doesn't emit overlapping entries, but tables built by hand might.
+pub fn handler_for_offset(entries: &[ExceptionEntry], offset: u32) -> Option<ExceptionEntry> {
+    entries
+        .iter()
+        .filter(|entry| entry.contains(offset))
+        .min_by_key(|entry| entry.end - entry.start)
+        .copied()
+}
@@ -0,0 +1,29 @@
+//! Helpers for the fuzz targets in `fuzz/`, exposed so crashes found by the fuzzer can be reproduced in a regular test.
+
+use crate::{dump_bytes, load_bytes, magic::PyVersion, objects_equal};
+
+/// The Python versions the fuzz targets load every input with.
+pub const VERSIONS: [(u8, u8); 6] = [(3, 6), (3, 7), (3, 10), (3, 11), (3, 12), (3, 13)];
+
+/// Loads `data`, dumps the result and loads it again, panicking if dumping fails or the second load doesn't give an equal
+/// object. The objects are compared with `objects_equal`, since references that aren't used by the object (e.g. a dict key
+/// that is dropped because its value is NULL) aren't written again. Data that can't be loaded is ignored, as long as
+/// loading returns an error instead of panicking.
+pub fn reproduce(data: &[u8], python_version: PyVersion) {
+    let Ok((object, references)) = load_bytes(data, python_version) else {
+        return;
+    };
+
+    let dumped = dump_bytes(object.clone(), Some(references.clone()), python_version, 4)
+        .expect("a loaded object can always be dumped");
+
+    let (reloaded, reloaded_references) =
+        load_bytes(&dumped, python_version).expect("dumped data can always be loaded");
+
+    assert!(
+        objects_equal(&object, &references, &reloaded, &reloaded_references),
+        "{:?} != {:?}",
+        object,
+        reloaded
+    );
+}
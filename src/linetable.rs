@@ -0,0 +1,195 @@
+//! Encoding and decoding of the location table (`co_linetable`) of Python 3.11+ code objects, see
+//! https://github.com/python/cpython/blob/main/InternalDocs/code_objects.md#format-of-the-locations-table
+
+use crate::error::Error;
+
+// Location entry codes, stored in bits 3-6 of the first byte of an entry
+const CODE_NO_COLUMNS: u8 = 13;
+const CODE_LONG: u8 = 14;
+const CODE_NONE: u8 = 15;
+
+// An entry covers at most 8 code units
+const MAX_ENTRY_UNITS: u32 = 8;
+const CODE_UNIT_SIZE: u32 = 2;
+
+fn write_varint(table: &mut Vec<u8>, mut value: u32) {
+    while value >= 64 {
+        table.push(0x40 | (value & 0x3f) as u8);
+        value >>= 6;
+    }
+    table.push(value as u8);
+}
+
+fn write_signed_varint(table: &mut Vec<u8>, value: i64) {
+    let value = if value < 0 {
+        ((value.unsigned_abs() as u32) << 1) | 1
+    } else {
+        (value as u32) << 1
+    };
+    write_varint(table, value);
+}
+
+fn write_entry(table: &mut Vec<u8>, code: u8, mut units: u32, line_delta: Option<i64>) {
+    let mut line_delta = line_delta;
+
+    while units > 0 {
+        let length = units.min(MAX_ENTRY_UNITS);
+        table.push(0x80 | (code << 3) | (length - 1) as u8);
+
+        if let Some(delta) = line_delta {
+            write_signed_varint(table, delta);
+            line_delta = Some(0); // The following entries are on the same line
+        }
+
+        units -= length;
+    }
+}
+
+/// Encodes `(bytecode_offset, line)` entries as a location table without column information.
+/// Every entry covers the bytecode from its offset up to the offset of the next entry, the last one up to `code_len`.
+/// Offsets are in bytes and have to be sorted, bytecode before the first entry gets no location.
+/// Lines are stored relative to `firstlineno`, which has to match the `firstlineno` of the code object.
+pub fn encode_linetable(entries: &[(u32, u32)], firstlineno: u32, code_len: u32) -> Vec<u8> {
+    let mut table = Vec::new();
+    let mut previous_line = firstlineno as i64;
+
+    if let Some((first_offset, _)) = entries.first() {
+        write_entry(&mut table, CODE_NONE, first_offset / CODE_UNIT_SIZE, None);
+    }
+
+    for (i, (offset, line)) in entries.iter().enumerate() {
+        let end = entries.get(i + 1).map_or(code_len, |(next, _)| *next);
+        let units = end.saturating_sub(*offset) / CODE_UNIT_SIZE;
+
+        if units == 0 {
+            continue;
+        }
+
+        write_entry(
+            &mut table,
+            CODE_NO_COLUMNS,
+            units,
+            Some(*line as i64 - previous_line),
+        );
+        previous_line = *line as i64;
+    }
+
+    table
+}
+
+/// Reads the location and exception tables, which both store their numbers as varints of 6-bit chunks.
+pub(crate) struct TableReader<'a> {
+    pub(crate) table: &'a [u8],
+    pub(crate) position: usize,
+}
+
+impl TableReader<'_> {
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        let byte = *self.table.get(self.position).ok_or(Error::TruncatedTable)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    /// Every chunk but the last one has bit 6 set. The location table stores the least significant chunk first,
+    /// the exception table the most significant one.
+    fn read_chunks(&mut self, most_significant_first: bool) -> Result<u32, Error> {
+        let mut value = 0u32;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.read_u8()?;
+            let chunk = (byte & 0x3f) as u32;
+
+            value = if most_significant_first {
+                value.checked_mul(64).ok_or(Error::InvalidConversion)? | chunk
+            } else {
+                value | chunk.checked_shl(shift).ok_or(Error::InvalidConversion)?
+            };
+            shift += 6;
+
+            if byte & 0x40 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn read_varint(&mut self) -> Result<u32, Error> {
+        self.read_chunks(false)
+    }
+
+    /// Reads a varint of the exception table, the first byte of an entry has bit 7 set on top of that.
+    pub(crate) fn read_exception_varint(&mut self) -> Result<u32, Error> {
+        self.read_chunks(true)
+    }
+
+    fn read_signed_varint(&mut self) -> Result<i64, Error> {
+        let value = self.read_varint()?;
+
+        if value & 1 == 1 {
+            Ok(-((value >> 1) as i64))
+        } else {
+            Ok((value >> 1) as i64)
+        }
+    }
+}
+
+/// Decodes a location table into `(bytecode_offset, line)` entries, in the format `encode_linetable` takes.
+/// Only the start of every run of bytecode on the same line is returned, column information and bytecode without a location are skipped.
+/// So only encoding and then decoding round-trips, encoding the decoded entries of a CPython table can give a different table
+/// (without columns, and a gap without a location becomes part of the entry before it).
+pub fn decode_linetable(table: &[u8], firstlineno: u32) -> Result<Vec<(u32, u32)>, Error> {
+    let mut reader = TableReader { table, position: 0 };
+    let mut entries: Vec<(u32, u32)> = Vec::new();
+    let mut line = firstlineno as i64;
+    let mut offset = 0;
+    // End offset of the last entry that had a line, so runs on the same line are merged
+    let mut line_end = None;
+
+    while reader.position < table.len() {
+        let first = reader.read_u8()?;
+        if first & 0x80 == 0 {
+            return Err(Error::InvalidConversion);
+        }
+
+        let code = (first >> 3) & 0x0f;
+        let units = (first & 0x07) as u32 + 1;
+
+        let has_line = match code {
+            CODE_NONE => false,
+            CODE_LONG => {
+                line += reader.read_signed_varint()?;
+                reader.read_varint()?; // End line delta
+                reader.read_varint()?; // Column
+                reader.read_varint()?; // End column
+                true
+            }
+            CODE_NO_COLUMNS => {
+                line += reader.read_signed_varint()?;
+                true
+            }
+            10..=12 => {
+                line += (code - 10) as i64;
+                reader.read_u8()?; // Column
+                reader.read_u8()?; // End column
+                true
+            }
+            _ => {
+                reader.read_u8()?; // Column
+                true
+            }
+        };
+
+        if has_line {
+            let current = u32::try_from(line).map_err(|_| Error::InvalidConversion)?;
+
+            if line_end != Some(offset) || entries.last().map(|(_, line)| *line) != Some(current) {
+                entries.push((offset, current));
+            }
+            line_end = Some(offset + units * CODE_UNIT_SIZE);
+        }
+
+        offset += units * CODE_UNIT_SIZE;
+    }
+
+    Ok(entries)
+}
@@ -1,3 +1,5 @@
+use std::path::{Path, PathBuf};
+
 #[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Debug, Ord)]
 pub struct PyVersion {
     pub major: u8,
@@ -13,6 +15,24 @@ impl PyVersion {
             patch: 0,
         }
     }
+
+    /// Returns the tag CPython puts in the name of cached pyc files (`sys.implementation.cache_tag`), e.g. `cpython-310` for 3.10.
+    pub fn cache_tag(&self) -> String {
+        format!("cpython-{}{}", self.major, self.minor)
+    }
+}
+
+/// Returns where the import system looks for the compiled `source` file (PEP 3147), the same as `importlib.util.cache_from_source`.
+/// E.g. `pkg/module.py` becomes `pkg/__pycache__/module.cpython-310.pyc` for 3.10.
+pub fn pyc_cache_path(source: &Path, version: PyVersion) -> PathBuf {
+    let mut filename = source.file_stem().unwrap_or_default().to_os_string();
+    filename.push(format!(".{}.pyc", version.cache_tag()));
+
+    source
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join("__pycache__")
+        .join(filename)
 }
 
 impl From<PyVersion> for String {
@@ -1,9 +1,12 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 
 use hashable::HashableHashSet;
-use indexmap::set::MutableValues;
+use indexmap::IndexSet;
+use num_bigint::BigInt;
 
-use crate::{Code, Object, ObjectHashable, optimize_references, unite_references};
+use crate::{
+    Code, Object, ObjectHashable, PyString, error::Error, optimize_references, unite_references,
+};
 
 /// Trait for transforming Python objects.
 // TODO: Don't use Sized to fix the error
@@ -30,6 +33,8 @@ pub trait Transformer {
             Object::Code(_) => self.visit_Code(obj),
             Object::LoadRef(_) => self.visit_LoadRef(obj),
             Object::StoreRef(_) => self.visit_StoreRef(obj),
+            Object::Raw { .. } => self.visit_Raw(obj),
+            Object::Unknown { .. } => self.visit_Unknown(obj),
         }
     }
 
@@ -91,9 +96,15 @@ pub trait Transformer {
 
     fn visit_Dict(&mut self, obj: &mut Object) -> Option<Object> {
         if let Object::Dict(dict) = obj {
-            for (_, value) in dict.iter_mut() {
-                value.transform(self);
-            }
+            // Changing the keys changes their hashes, so the dict has to be rebuilt
+            *dict = std::mem::take(dict)
+                .into_iter()
+                .map(|(mut key, mut value)| {
+                    key.transform(self);
+                    value.transform(self);
+                    (key, value)
+                })
+                .collect();
         }
 
         None
@@ -101,10 +112,14 @@ pub trait Transformer {
 
     fn visit_Set(&mut self, obj: &mut Object) -> Option<Object> {
         if let Object::Set(set) = obj {
-            for i in 0..set.len() {
-                let obj = set.get_index_mut2(i)?;
-                obj.transform(self);
-            }
+            // Changing the elements changes their hashes, so the set has to be rebuilt
+            *set = std::mem::take(set)
+                .into_iter()
+                .map(|mut obj| {
+                    obj.transform(self);
+                    obj
+                })
+                .collect();
         }
 
         None
@@ -112,10 +127,14 @@ pub trait Transformer {
 
     fn visit_FrozenSet(&mut self, obj: &mut Object) -> Option<Object> {
         if let Object::FrozenSet(set) = obj {
-            for i in 0..set.len() {
-                let obj = set.get_index_mut2(i)?;
-                obj.transform(self);
-            }
+            // Changing the elements changes their hashes, so the set has to be rebuilt
+            *set = std::mem::take(set)
+                .into_iter()
+                .map(|mut obj| {
+                    obj.transform(self);
+                    obj
+                })
+                .collect();
         }
 
         None
@@ -124,6 +143,17 @@ pub trait Transformer {
     fn visit_Code(&mut self, obj: &mut Object) -> Option<Object> {
         if let Object::Code(code) = obj {
             match *code {
+                Code::V30(ref mut code) => {
+                    code.code.transform(self);
+                    code.consts.transform(self);
+                    code.names.transform(self);
+                    code.varnames.transform(self);
+                    code.freevars.transform(self);
+                    code.cellvars.transform(self);
+                    code.filename.transform(self);
+                    code.name.transform(self);
+                    code.lnotab.transform(self);
+                }
                 Code::V310(ref mut code) => {
                     code.code.transform(self);
                     code.consts.transform(self);
@@ -185,6 +215,14 @@ pub trait Transformer {
         None
     }
 
+    fn visit_Raw(&mut self, obj: &mut Object) -> Option<Object> {
+        None
+    }
+
+    fn visit_Unknown(&mut self, obj: &mut Object) -> Option<Object> {
+        None
+    }
+
     /// Same as `visit`, but for hashable objects.
     fn visit_Hashable(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
         match obj {
@@ -274,6 +312,7 @@ pub trait Transformer {
     }
 }
 
+/// Applies a `Transformer` to an object, replacing it with the transformed version.
 pub trait Transformable {
     fn transform(&mut self, transformer: &mut (impl Transformer + ?Sized));
 }
@@ -298,13 +337,13 @@ impl Transformable for ObjectHashable {
 pub struct ReferenceOptimizer<'a> {
     pub references: &'a [Object],
     pub new_references: Vec<Object>,
-    pub references_used: HashSet<usize>,
+    pub references_used: IndexSet<usize>,
     /// Map of old index to new index
     reference_map: HashMap<usize, usize>,
 }
 
 impl<'a> ReferenceOptimizer<'a> {
-    pub fn new(references: &'a [Object], references_used: HashSet<usize>) -> Self {
+    pub fn new(references: &'a [Object], references_used: IndexSet<usize>) -> Self {
         Self {
             references,
             new_references: Vec::new(),
@@ -476,14 +515,14 @@ impl Transformer for ReferenceUniter<'_> {
 /// Creates a set of used references from a given object and a list of references.
 struct ReferenceCounter<'a> {
     pub references: &'a [Object],
-    pub references_used: HashSet<usize>, // Indexes of references that are used
+    pub references_used: IndexSet<usize>, // Indexes of references that are used
 }
 
 impl<'a> ReferenceCounter<'a> {
     pub fn new(references: &'a [Object]) -> Self {
         Self {
             references,
-            references_used: HashSet::new(),
+            references_used: IndexSet::new(),
         }
     }
 }
@@ -528,7 +567,7 @@ impl Transformer for ReferenceCounter<'_> {
     }
 }
 
-pub fn get_used_references(obj: &mut Object, references: &[Object]) -> HashSet<usize> {
+pub fn get_used_references(obj: &mut Object, references: &[Object]) -> IndexSet<usize> {
     let mut counter = ReferenceCounter::new(references);
 
     obj.transform(&mut counter);
@@ -550,10 +589,10 @@ impl Transformer for ReferenceEverything {
     fn visit(&mut self, obj: &mut Object) -> Option<Object> {
         // Make sure inner objects are also references
         match obj {
-            Object::None => self.visit_None(obj),
-            Object::StopIteration => self.visit_StopIteration(obj),
-            Object::Ellipsis => self.visit_Ellipsis(obj),
-            Object::Bool(_) => self.visit_Bool(obj),
+            // Never stored as references, see `Object::is_ref_eligible`
+            Object::None | Object::StopIteration | Object::Ellipsis | Object::Bool(_) => {
+                return None;
+            }
             Object::Long(_) => self.visit_Long(obj),
             Object::Float(_) => self.visit_Float(obj),
             Object::Complex(_) => self.visit_Complex(obj),
@@ -565,7 +604,7 @@ impl Transformer for ReferenceEverything {
             Object::Set(_) => self.visit_Set(obj),
             Object::FrozenSet(_) => self.visit_FrozenSet(obj),
             Object::Code(_) => self.visit_Code(obj),
-            Object::LoadRef(_) => {
+            Object::LoadRef(_) | Object::Raw { .. } | Object::Unknown { .. } => {
                 return None;
             }
             Object::StoreRef(index) => {
@@ -584,10 +623,12 @@ impl Transformer for ReferenceEverything {
     fn visit_Hashable(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
         // Make sure inner objects are also references
         match obj {
-            ObjectHashable::None => self.visit_HashableNone(obj),
-            ObjectHashable::StopIteration => self.visit_HashableStopIteration(obj),
-            ObjectHashable::Ellipsis => self.visit_HashableEllipsis(obj),
-            ObjectHashable::Bool(_) => self.visit_HashableBool(obj),
+            ObjectHashable::None
+            | ObjectHashable::StopIteration
+            | ObjectHashable::Ellipsis
+            | ObjectHashable::Bool(_) => {
+                return None;
+            }
             ObjectHashable::Long(_) => self.visit_HashableLong(obj),
             ObjectHashable::Float(_) => self.visit_HashableFloat(obj),
             ObjectHashable::Complex(_) => self.visit_HashableComplex(obj),
@@ -624,3 +665,279 @@ pub fn minimize_references(object: &Object, references: Vec<Object>) -> (Object,
 
     optimize_references(&object, &references)
 }
+
+/// Replaces integer constants, e.g. to patch magic numbers in the `consts` of code objects. Both `Object::Long` and the ints
+/// in dict keys and set elements are replaced. A replacement can cross the i32 boundary, the writer picks `Kind::Int` or
+/// `Kind::Long` based on the new value. Ints in the reference table are only replaced when the references are transformed too.
+pub struct IntReplacer {
+    replacements: HashMap<BigInt, BigInt>,
+}
+
+impl IntReplacer {
+    pub fn new(replacements: HashMap<BigInt, BigInt>) -> Self {
+        Self { replacements }
+    }
+}
+
+impl Transformer for IntReplacer {
+    fn visit_Long(&mut self, obj: &mut Object) -> Option<Object> {
+        if let Object::Long(value) = obj {
+            self.replacements.get(value).cloned().map(Object::Long)
+        } else {
+            None
+        }
+    }
+
+    fn visit_HashableLong(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
+        if let ObjectHashable::Long(value) = obj {
+            self.replacements
+                .get(value)
+                .cloned()
+                .map(ObjectHashable::Long)
+        } else {
+            None
+        }
+    }
+}
+
+/// Calls a function on every string, see `Object::map_strings`.
+struct StringMapper<F: FnMut(&mut PyString)> {
+    f: F,
+}
+
+impl<F: FnMut(&mut PyString)> Transformer for StringMapper<F> {
+    fn visit_String(&mut self, obj: &mut Object) -> Option<Object> {
+        if let Object::String(string) = obj {
+            (self.f)(string);
+        }
+
+        None
+    }
+
+    fn visit_HashableString(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
+        if let ObjectHashable::String(string) = obj {
+            (self.f)(string);
+        }
+
+        None
+    }
+}
+
+impl Object {
+    /// Calls `f` on every string in the object, including dict keys and set elements (which are rehashed afterwards).
+    /// References aren't followed, call it on the reference table as well (or resolve the references first).
+    pub fn map_strings(&mut self, f: impl FnMut(&mut PyString)) {
+        self.transform(&mut StringMapper { f });
+    }
+}
+
+/// Adds an offset to every reference index, used to move a reference table behind another one.
+struct ReferenceShifter {
+    offset: usize,
+}
+
+impl Transformer for ReferenceShifter {
+    fn visit_LoadRef(&mut self, obj: &mut Object) -> Option<Object> {
+        if let Object::LoadRef(index) = obj {
+            Some(Object::LoadRef(*index + self.offset))
+        } else {
+            None
+        }
+    }
+
+    fn visit_StoreRef(&mut self, obj: &mut Object) -> Option<Object> {
+        if let Object::StoreRef(index) = obj {
+            Some(Object::StoreRef(*index + self.offset))
+        } else {
+            None
+        }
+    }
+
+    fn visit_HashableLoadRef(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
+        if let ObjectHashable::LoadRef(index) = obj {
+            Some(ObjectHashable::LoadRef(*index + self.offset))
+        } else {
+            None
+        }
+    }
+
+    fn visit_HashableStoreRef(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
+        if let ObjectHashable::StoreRef(index) = obj {
+            Some(ObjectHashable::StoreRef(*index + self.offset))
+        } else {
+            None
+        }
+    }
+}
+
+/// Combines two objects that were loaded separately (e.g. from two pyc files) into one reference table.
+/// The references of `b` are placed behind the ones of `a` and every index in `b` is shifted accordingly.
+/// The indices no longer match the order in which they're written, so run `optimize_references` on the object you dump.
+pub fn merge_references(
+    a: (Object, Vec<Object>),
+    b: (Object, Vec<Object>),
+) -> (Object, Object, Vec<Object>) {
+    let (a, mut references) = a;
+    let (mut b, b_references) = b;
+
+    let mut shifter = ReferenceShifter {
+        offset: references.len(),
+    };
+
+    b.transform(&mut shifter);
+
+    for mut reference in b_references {
+        reference.transform(&mut shifter);
+        references.push(reference);
+    }
+
+    (a, b, references)
+}
+
+/// Appends an object to the reference table and returns the `StoreRef` to it, later uses should be `Object::LoadRef` with the same index.
+/// The reader numbers references in the order it encounters them, so objects have to be pushed in the order their `StoreRef`s are written.
+/// Use `ReferenceBuilder` when that order isn't known up front.
+pub fn push_reference(references: &mut Vec<Object>, obj: Object) -> Object {
+    references.push(obj);
+
+    Object::StoreRef(references.len() - 1)
+}
+
+/// Builds a consistent reference table for objects that are constructed programmatically.
+/// Add shared objects with `intern` and place `Object::LoadRef(index)` wherever they are used, `build` then turns the first use into a `StoreRef` and numbers the references in the order they are written.
+#[derive(Debug, Default)]
+pub struct ReferenceBuilder {
+    references: Vec<Object>,
+}
+
+impl ReferenceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an object to the reference table and returns its index. Interning an equal object again returns the existing index.
+    pub fn intern(&mut self, obj: Object) -> usize {
+        match self
+            .references
+            .iter()
+            .position(|reference| *reference == obj)
+        {
+            Some(index) => index,
+            None => {
+                self.references.push(obj);
+                self.references.len() - 1
+            }
+        }
+    }
+
+    /// Interns the object and returns the `LoadRef` to place in the object tree.
+    pub fn reference(&mut self, obj: Object) -> Object {
+        Object::LoadRef(self.intern(obj))
+    }
+
+    /// Returns the object and reference table ready to be passed to `dump_bytes`. References that are never used are left out.
+    pub fn build(&self, object: &Object) -> Result<(Object, Vec<Object>), Error> {
+        place_references(object, &self.references)
+    }
+}
+
+/// Renumbers the references in the order the reader will encounter them, the first use of every reference (`LoadRef` or
+/// `StoreRef`) becomes the `StoreRef`. Used after objects were removed that stored references which are loaded elsewhere.
+pub(crate) fn place_references(
+    object: &Object,
+    references: &[Object],
+) -> Result<(Object, Vec<Object>), Error> {
+    let mut object = object.clone();
+
+    let mut placer = ReferencePlacer::new(references);
+
+    object.transform(&mut placer);
+
+    match placer.error {
+        Some(error) => Err(error),
+        None => Ok((object, placer.new_references)),
+    }
+}
+
+/// Places the references of a `ReferenceBuilder`: the first use becomes a StoreRef and later uses a LoadRef, numbered in the order the reader will encounter them.
+struct ReferencePlacer<'a> {
+    references: &'a [Object],
+    new_references: Vec<Object>,
+    /// Map of old index to new index
+    reference_map: HashMap<usize, usize>,
+    error: Option<Error>,
+}
+
+impl<'a> ReferencePlacer<'a> {
+    fn new(references: &'a [Object]) -> Self {
+        Self {
+            references,
+            new_references: Vec::new(),
+            reference_map: HashMap::new(),
+            error: None,
+        }
+    }
+
+    /// Returns the new reference to place, or the object itself if it can't be stored as a reference.
+    fn place(&mut self, index: usize) -> Option<Object> {
+        if let Some(new_index) = self.reference_map.get(&index) {
+            return Some(Object::LoadRef(*new_index));
+        }
+
+        let Some(reference) = self.references.get(index) else {
+            self.error = Some(Error::InvalidReference(index));
+            return None;
+        };
+
+        match reference {
+            // The reader never stores these as references
+            reference if !reference.is_ref_eligible() => Some(reference.clone()),
+            _ => {
+                // Reserve the index before the inner objects, just like the reader does
+                let new_index = self.new_references.len();
+                self.new_references.push(Object::None);
+                self.reference_map.insert(index, new_index);
+
+                let mut obj = reference.clone();
+                obj.transform(self);
+                self.new_references[new_index] = obj;
+
+                Some(Object::StoreRef(new_index))
+            }
+        }
+    }
+}
+
+impl Transformer for ReferencePlacer<'_> {
+    fn visit_LoadRef(&mut self, obj: &mut Object) -> Option<Object> {
+        if let Object::LoadRef(index) = obj {
+            self.place(*index)
+        } else {
+            None
+        }
+    }
+
+    fn visit_StoreRef(&mut self, obj: &mut Object) -> Option<Object> {
+        if let Object::StoreRef(index) = obj {
+            self.place(*index)
+        } else {
+            None
+        }
+    }
+
+    fn visit_HashableLoadRef(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
+        if let ObjectHashable::LoadRef(index) | ObjectHashable::StoreRef(index) = obj {
+            match self.place(*index)? {
+                Object::LoadRef(index) => Some(ObjectHashable::LoadRef(index)),
+                Object::StoreRef(index) => Some(ObjectHashable::StoreRef(index)),
+                obj => ObjectHashable::try_from(obj).ok(),
+            }
+        } else {
+            None
+        }
+    }
+
+    fn visit_HashableStoreRef(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
+        self.visit_HashableLoadRef(obj)
+    }
+}
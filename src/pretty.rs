@@ -0,0 +1,216 @@
+use std::fmt::Write;
+
+use crate::{Code, Object};
+
+/// Lists the fields of a Code31x object (Python 3.11, 3.12, 3.13) which share the same structure
+macro_rules! code311_fields {
+    ($code:ident) => {
+        (
+            vec![
+                ("argcount", $code.argcount),
+                ("posonlyargcount", $code.posonlyargcount),
+                ("kwonlyargcount", $code.kwonlyargcount),
+                ("stacksize", $code.stacksize),
+                ("firstlineno", $code.firstlineno),
+            ],
+            &$code.flags,
+            vec![
+                ("code", &*$code.code),
+                ("consts", &*$code.consts),
+                ("names", &*$code.names),
+                ("localsplusnames", &*$code.localsplusnames),
+                ("localspluskinds", &*$code.localspluskinds),
+                ("filename", &*$code.filename),
+                ("name", &*$code.name),
+                ("qualname", &*$code.qualname),
+                ("linetable", &*$code.linetable),
+                ("exceptiontable", &*$code.exceptiontable),
+            ],
+        )
+    };
+}
+
+const INDENT: &str = "    ";
+
+/// Formats an object as an indented, multi-line tree with all references resolved. References that point back to an
+/// object that is being printed are shown as `<cycle ref N>`. Containers and code objects nested deeper than
+/// `max_depth` are elided with `...`, a `max_depth` of 0 only prints the root.
+pub fn pretty_print(obj: &Object, references: &[Object], max_depth: usize) -> String {
+    let mut printer = PrettyPrinter {
+        references,
+        max_depth,
+        output: String::new(),
+        resolving: Vec::new(),
+    };
+
+    printer.object(obj, 0);
+
+    printer.output
+}
+
+struct PrettyPrinter<'a> {
+    references: &'a [Object],
+    max_depth: usize,
+    output: String,
+    resolving: Vec<usize>, // Indexes of the references that are currently being printed
+}
+
+impl PrettyPrinter<'_> {
+    fn indent(&mut self, depth: usize) {
+        for _ in 0..depth {
+            self.output.push_str(INDENT);
+        }
+    }
+
+    fn object(&mut self, obj: &Object, depth: usize) {
+        match obj {
+            Object::LoadRef(index) | Object::StoreRef(index) => self.reference(*index, depth),
+            Object::Tuple(objs) => self.sequence("(", ")", objs, depth),
+            Object::List(objs) => self.sequence("[", "]", objs, depth),
+            Object::Set(set) if set.is_empty() => self.output.push_str("set()"),
+            Object::Set(set) => {
+                let objs: Vec<Object> = set.iter().cloned().map(Object::from).collect();
+                self.sequence("{", "}", &objs, depth);
+            }
+            Object::FrozenSet(set) if set.is_empty() => self.output.push_str("frozenset()"),
+            Object::FrozenSet(set) => {
+                let objs: Vec<Object> = set.iter().cloned().map(Object::from).collect();
+                self.sequence("frozenset({", "})", &objs, depth);
+            }
+            Object::Dict(dict) => {
+                if dict.is_empty() {
+                    self.output.push_str("{}");
+                } else if depth >= self.max_depth {
+                    self.output.push_str("{...}");
+                } else {
+                    self.output.push_str("{\n");
+                    for (key, value) in dict {
+                        self.indent(depth + 1);
+                        self.object(&Object::from(key.clone()), depth + 1);
+                        self.output.push_str(": ");
+                        self.object(value, depth + 1);
+                        self.output.push_str(",\n");
+                    }
+                    self.indent(depth);
+                    self.output.push('}');
+                }
+            }
+            Object::Code(code) => self.code(code, depth),
+            _ => write!(self.output, "{}", obj).expect("writing to a String can't fail"),
+        }
+    }
+
+    fn reference(&mut self, index: usize, depth: usize) {
+        if self.resolving.contains(&index) {
+            write!(self.output, "<cycle ref {}>", index).expect("writing to a String can't fail");
+            return;
+        }
+
+        match self.references.get(index) {
+            Some(obj) => {
+                self.resolving.push(index);
+                self.object(obj, depth);
+                self.resolving.pop();
+            }
+            None => write!(self.output, "<invalid ref {}>", index)
+                .expect("writing to a String can't fail"),
+        }
+    }
+
+    fn sequence(&mut self, open: &str, close: &str, objs: &[Object], depth: usize) {
+        self.output.push_str(open);
+
+        if objs.is_empty() {
+            // Nothing to print
+        } else if depth >= self.max_depth {
+            self.output.push_str("...");
+        } else {
+            self.output.push('\n');
+            for obj in objs {
+                self.indent(depth + 1);
+                self.object(obj, depth + 1);
+                self.output.push_str(",\n");
+            }
+            self.indent(depth);
+        }
+
+        self.output.push_str(close);
+    }
+
+    fn code(&mut self, code: &Code, depth: usize) {
+        if depth >= self.max_depth {
+            self.output.push_str("Code {...}");
+            return;
+        }
+
+        let (values, flags, objs) = match code {
+            Code::V30(code) => (
+                vec![
+                    ("argcount", code.argcount),
+                    ("kwonlyargcount", code.kwonlyargcount),
+                    ("nlocals", code.nlocals),
+                    ("stacksize", code.stacksize),
+                    ("firstlineno", code.firstlineno),
+                ],
+                &code.flags,
+                vec![
+                    ("code", &*code.code),
+                    ("consts", &*code.consts),
+                    ("names", &*code.names),
+                    ("varnames", &*code.varnames),
+                    ("freevars", &*code.freevars),
+                    ("cellvars", &*code.cellvars),
+                    ("filename", &*code.filename),
+                    ("name", &*code.name),
+                    ("lnotab", &*code.lnotab),
+                ],
+            ),
+            Code::V310(code) => (
+                vec![
+                    ("argcount", code.argcount),
+                    ("posonlyargcount", code.posonlyargcount),
+                    ("kwonlyargcount", code.kwonlyargcount),
+                    ("nlocals", code.nlocals),
+                    ("stacksize", code.stacksize),
+                    ("firstlineno", code.firstlineno),
+                ],
+                &code.flags,
+                vec![
+                    ("code", &*code.code),
+                    ("consts", &*code.consts),
+                    ("names", &*code.names),
+                    ("varnames", &*code.varnames),
+                    ("freevars", &*code.freevars),
+                    ("cellvars", &*code.cellvars),
+                    ("filename", &*code.filename),
+                    ("name", &*code.name),
+                    ("linetable", &*code.linetable),
+                ],
+            ),
+            Code::V311(code) => code311_fields!(code),
+            Code::V312(code) => code311_fields!(code),
+            Code::V313(code) => code311_fields!(code),
+        };
+
+        self.output.push_str("Code {\n");
+
+        for (name, value) in values {
+            self.indent(depth + 1);
+            writeln!(self.output, "{}: {}", name, value).expect("writing to a String can't fail");
+        }
+
+        self.indent(depth + 1);
+        writeln!(self.output, "flags: {}", flags.names().join(" | "))
+            .expect("writing to a String can't fail");
+
+        for (name, obj) in objs {
+            self.indent(depth + 1);
+            write!(self.output, "{}: ", name).expect("writing to a String can't fail");
+            self.object(obj, depth + 1);
+            self.output.push('\n');
+        }
+
+        self.indent(depth);
+        self.output.push('}');
+    }
+}
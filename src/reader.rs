@@ -1,4 +1,5 @@
-use core::panic;
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::io::{Cursor, Read};
 
 use bstr::BString;
@@ -8,7 +9,8 @@ use num_complex::Complex;
 use num_traits::FromPrimitive;
 
 use crate::{
-    Code, CodeFlags, Kind, Object, ObjectHashable, PyString, PyVersion, code_objects, error::Error,
+    Code, CodeFlags, Kind, Object, ObjectHashable, PyString, PyVersion, RecoveredError,
+    code_objects, error::Error, writer::collect_reference_indices,
 };
 
 /// On windows this is 1000.
@@ -20,13 +22,36 @@ static MAX_DEPTH: usize = 1000;
 #[cfg(not(windows))]
 static MAX_DEPTH: usize = 2000;
 
-/// A reader for Python marshal data.
-pub struct PyReader {
-    cursor: Cursor<Vec<u8>>,
+/// The default maximum amount of references, so crafted data can't exhaust memory through the reference table.
+pub const MAX_REFERENCES: usize = 10_000_000;
+
+/// A reader for Python marshal data. The data is either owned (`new`) or borrowed (`from_slice`).
+pub struct PyReader<'a> {
+    cursor: Cursor<Cow<'a, [u8]>>,
     pub references: Vec<Object>,
     version: PyVersion,
     /// The current depth of the object being read.
     depth: usize,
+    /// The maximum depth before reading fails, see `with_max_depth`.
+    max_depth: usize,
+    /// The maximum amount of references the reference table can hold.
+    max_references: usize,
+    /// Whether to skip the bytecode, constants and line table of code objects, see `with_lazy_code`.
+    lazy_code: bool,
+    /// The lowest marshal version that writes all the kinds read so far, see `min_marshal_version`.
+    min_marshal_version: u8,
+    /// Called with the size of the reference table every time a reference is stored, see `with_progress`.
+    on_object: Option<Box<dyn FnMut(usize) + 'a>>,
+    /// Reference slots of the containers that are being read, a `Ref` to one of them is a recursive reference.
+    reserved: Vec<usize>,
+    /// Reference slots of containers that failed to read, they're never filled so a `Ref` to one of them is invalid.
+    dangling: HashSet<usize>,
+    /// Reference slots stored by dict keys that were dropped because their value was NULL, the first `Ref` to one of them becomes its `StoreRef`.
+    orphaned: HashSet<usize>,
+    /// Whether to replace unreadable objects with `Object::Unknown` instead of failing, see `with_tolerant`.
+    tolerant: bool,
+    /// The errors that were recovered from in tolerant mode, with the offset of the object they occurred at.
+    errors: Vec<RecoveredError>,
 }
 
 /// Extracts an object from a result, matching it against a specific variant.
@@ -140,16 +165,147 @@ macro_rules! extract_strings_dict {
     };
 }
 
-impl PyReader {
+/// Whether the reference slot of `kind` is reserved before its contents are read, like CPython's `r_ref_reserve`.
+/// Containers and code objects can contain references to themselves, other objects get their slot after they're read.
+fn reserves_reference(kind: Kind) -> bool {
+    matches!(
+        kind,
+        Kind::SmallTuple
+            | Kind::Tuple
+            | Kind::List
+            | Kind::Dict
+            | Kind::Set
+            | Kind::FrozenSet
+            | Kind::Code
+    )
+}
+
+/// Returns the marshal version that introduced `kind`, references (`flag`) were added in version 3.
+/// See https://github.com/python/cpython/blob/3.13/Python/marshal.c
+fn kind_marshal_version(kind: Kind, flag: bool) -> u8 {
+    let version = match kind {
+        Kind::SmallTuple
+        | Kind::ASCII
+        | Kind::ASCIIInterned
+        | Kind::ShortAscii
+        | Kind::ShortAsciiInterned => 4,
+        Kind::Ref => 3,
+        Kind::BinaryFloat | Kind::BinaryComplex => 2,
+        Kind::Interned => 1,
+        _ => 0,
+    };
+
+    if flag { version.max(3) } else { version }
+}
+
+impl<'a> PyReader<'a> {
     pub fn new(data: Vec<u8>, version: PyVersion) -> Self {
+        Self::from_cow(Cow::Owned(data), version)
+    }
+
+    /// Same as `new`, but borrows the data instead of taking ownership, so it doesn't have to be copied.
+    pub fn from_slice(data: &'a [u8], version: PyVersion) -> Self {
+        Self::from_cow(Cow::Borrowed(data), version)
+    }
+
+    fn from_cow(data: Cow<'a, [u8]>, version: PyVersion) -> Self {
         Self {
             cursor: Cursor::new(data),
             version,
             references: Vec::new(),
             depth: 0,
+            max_depth: MAX_DEPTH,
+            min_marshal_version: 0,
+            max_references: MAX_REFERENCES,
+            lazy_code: false,
+            on_object: None,
+            reserved: Vec::new(),
+            dangling: HashSet::new(),
+            orphaned: HashSet::new(),
+            tolerant: false,
+            errors: Vec::new(),
         }
     }
 
+    /// Creates a reader that starts reading at `position` with an existing reference table, used to decode `Object::Raw` ranges.
+    pub(crate) fn at_position(
+        data: &'a [u8],
+        version: PyVersion,
+        position: u64,
+        references: Vec<Object>,
+    ) -> Self {
+        let mut reader = Self::from_slice(data, version);
+        reader.seek_to(position);
+        reader.references = references;
+        reader
+    }
+
+    /// Sets the maximum amount of references the reference table can hold before reading fails.
+    pub fn with_max_references(mut self, max_references: usize) -> Self {
+        self.max_references = max_references;
+        self
+    }
+
+    /// Sets the maximum nesting depth of the objects that can be read, which defaults to the limit of CPython (2000, 1000 on Windows).
+    /// Every level of nesting is a recursive call, so a very high limit can overflow the stack.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Sets a callback that is called every time an object is stored in the reference table, with the amount of references stored so far.
+    /// Useful to report progress when reading large modules.
+    pub fn with_progress(mut self, on_object: impl FnMut(usize) + 'a) -> Self {
+        self.on_object = Some(Box::new(on_object));
+        self
+    }
+
+    /// Moves the reader to the given byte offset, so the next object is read from there.
+    pub fn seek_to(&mut self, pos: u64) {
+        self.cursor.set_position(pos);
+    }
+
+    /// Returns the byte offset the next object will be read from.
+    pub fn position(&self) -> u64 {
+        self.cursor.position()
+    }
+
+    /// Returns the lowest marshal version that could have written the objects read so far, e.g. 4 once a `SmallTuple` is read.
+    /// This is only a lower bound, CPython doesn't use every feature of a marshal version in every object.
+    pub fn min_marshal_version(&self) -> u8 {
+        self.min_marshal_version
+    }
+
+    /// When enabled, the bytecode, constants and line table of code objects are skipped and stored as `Object::Raw` byte ranges.
+    /// References stored inside a skipped range get an `Object::Raw` placeholder in the reference table, so all other indices stay the same.
+    pub fn with_lazy_code(mut self, lazy_code: bool) -> Self {
+        self.lazy_code = lazy_code;
+        self
+    }
+
+    /// When enabled, an object with an invalid kind or a reference outside of the reference table is read as `Object::Unknown`
+    /// and reading continues after it. The errors are collected instead, see `take_errors`. Other errors (e.g. truncated data) still fail.
+    pub fn with_tolerant(mut self, tolerant: bool) -> Self {
+        self.tolerant = tolerant;
+        self
+    }
+
+    /// Returns the errors recovered from in tolerant mode so far, with the offset of the object they occurred at, and clears them.
+    pub fn take_errors(&mut self) -> Vec<RecoveredError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Returns a placeholder for the object at `offset` and records the error in tolerant mode, otherwise returns the error.
+    fn recover(&mut self, offset: u64, error: Error) -> Result<Object, Error> {
+        if !self.tolerant {
+            return Err(error);
+        }
+
+        self.errors.push((offset, error));
+
+        Ok(Object::Unknown { offset })
+    }
+
     fn r_u8(&mut self) -> Result<u8, std::io::Error> {
         let mut buf = [0; 1];
         self.cursor.read_exact(&mut buf)?;
@@ -163,6 +319,7 @@ impl PyReader {
         Ok(value)
     }
 
+    // Marshal data is little-endian on every platform, all integers and floats are read with `from_le_bytes`
     fn r_long(&mut self) -> Result<i32, std::io::Error> {
         let mut buf = [0; 4];
         self.cursor.read_exact(&mut buf)?;
@@ -177,7 +334,16 @@ impl PyReader {
         Ok(value)
     }
 
+    /// Amount of bytes left to read, used to reject lengths that can't fit in the data before allocating them.
+    fn remaining(&self) -> usize {
+        (self.cursor.get_ref().len() as u64).saturating_sub(self.cursor.position()) as usize
+    }
+
     fn r_bytes(&mut self, length: usize) -> Result<Vec<u8>, std::io::Error> {
+        if length > self.remaining() {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        }
+
         let mut buf = vec![0; length];
         self.cursor.read_exact(&mut buf)?;
         Ok(buf)
@@ -204,7 +370,8 @@ impl PyReader {
 
     fn r_vec(&mut self, length: usize, kind: Kind) -> Result<Vec<Object>, Error> {
         let mut vec = Vec::new();
-        vec.try_reserve(length).map_err(|_| {
+        // Every object takes at least one byte, so don't reserve more than the data can hold
+        vec.try_reserve(length.min(self.remaining())).map_err(|_| {
             Error::InvalidData(std::io::Error::from(std::io::ErrorKind::OutOfMemory))
         })?;
 
@@ -229,12 +396,25 @@ impl PyReader {
         let mut map = IndexMap::new();
 
         loop {
+            let offset = self.cursor.position();
+
             match self.r_object()? {
                 None => break,
                 Some(key) => match self.r_object()? {
-                    None => break,
+                    None => {
+                        // CPython drops the key as well, but a later object can still reference it
+                        self.orphaned.extend(self.stored_indices(&key));
+                        break;
+                    }
                     Some(value) => {
-                        map.insert(ObjectHashable::try_from(key)?, value);
+                        let is_unknown = matches!(key, Object::Unknown { .. });
+
+                        match ObjectHashable::try_from(key) {
+                            Ok(key) => {
+                                map.insert(key, value);
+                            }
+                            Err(error) => self.skip_unhashable(is_unknown, offset, error)?,
+                        }
                     }
                 },
             }
@@ -243,37 +423,293 @@ impl PyReader {
         Ok(map)
     }
 
+    /// Returns the index of every reference the object stores, including the ones stored by the referenced objects.
+    fn stored_indices(&self, obj: &Object) -> HashSet<usize> {
+        let mut stored = HashSet::new();
+        let mut pending = vec![obj];
+
+        while let Some(obj) = pending.pop() {
+            let mut indices = Vec::new();
+            collect_reference_indices(obj, &mut indices);
+
+            for (index, is_store) in indices {
+                if is_store && stored.insert(index) {
+                    pending.extend(self.references.get(index));
+                }
+            }
+        }
+
+        stored
+    }
+
+    /// Reads the elements of a set or frozenset, which have to be hashable.
+    fn r_set(
+        &mut self,
+        length: usize,
+        kind: Kind,
+        offset: u64,
+    ) -> Result<IndexSet<ObjectHashable>, Error> {
+        let mut set = IndexSet::new();
+
+        for obj in self.r_vec(length, kind)? {
+            let is_unknown = matches!(obj, Object::Unknown { .. });
+
+            match ObjectHashable::from_ref(obj, &self.references) {
+                Ok(obj) => {
+                    set.insert(obj);
+                }
+                Err(_) => self.skip_unhashable(is_unknown, offset, Error::UnexpectedObject)?,
+            }
+        }
+
+        Ok(set)
+    }
+
+    /// In tolerant mode, a set element or dict key that isn't hashable is left out instead of failing. A placeholder was already
+    /// recorded when it was read, any other object is recorded at `offset` (the set or the key).
+    fn skip_unhashable(
+        &mut self,
+        is_unknown: bool,
+        offset: u64,
+        error: Error,
+    ) -> Result<(), Error> {
+        if !self.tolerant {
+            return Err(error);
+        }
+
+        if !is_unknown {
+            self.errors.push((offset, error));
+        }
+
+        Ok(())
+    }
+
+    /// Adds an object to the reference table and returns its index.
+    fn push_reference(&mut self, obj: Object) -> Result<usize, Error> {
+        if self.references.len() >= self.max_references {
+            return Err(Error::InvalidData(std::io::Error::from(
+                std::io::ErrorKind::OutOfMemory,
+            )));
+        }
+
+        self.references.push(obj);
+
+        if let Some(on_object) = &mut self.on_object {
+            on_object(self.references.len());
+        }
+
+        Ok(self.references.len() - 1)
+    }
+
+    /// Reserves a reference slot for a container before its contents are read, the placeholder is replaced with `set_reference`.
+    fn reserve_reference(&mut self) -> Result<usize, Error> {
+        let index = self.push_reference(Object::None)?;
+        self.reserved.push(index);
+
+        Ok(index)
+    }
+
     fn set_reference(&mut self, index: usize, obj: Object) {
+        // Containers are filled in the reverse order they're reserved in
+        if self.reserved.last() == Some(&index) {
+            self.reserved.pop();
+        }
+
         self.references[index] = obj;
     }
 
-    fn r_object(&mut self) -> Result<Option<Object>, Error> {
+    /// Reads a code object field that is skipped in lazy mode.
+    fn r_code_body(&mut self) -> Result<Object, Error> {
+        if !self.lazy_code {
+            return self.r_object()?.ok_or(Error::UnexpectedNull);
+        }
+
+        let start = self.cursor.position() as usize;
+
+        if !self.skip_object()? {
+            return Err(Error::UnexpectedNull);
+        }
+
+        Ok(Object::Raw {
+            start,
+            len: self.cursor.position() as usize - start,
+        })
+    }
+
+    fn skip_bytes(&mut self, length: usize) -> Result<(), Error> {
+        let position = self.cursor.position().saturating_add(length as u64);
+
+        if position > self.cursor.get_ref().len() as u64 {
+            return Err(Error::InvalidData(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            )));
+        }
+
+        self.cursor.set_position(position);
+
+        Ok(())
+    }
+
+    /// Skips over an object without decoding it, while keeping the reference table in sync with `r_object`.
+    /// Returns false if the object was NULL.
+    fn skip_object(&mut self) -> Result<bool, Error> {
         self.depth += 1;
 
-        if self.depth > MAX_DEPTH {
+        if self.depth > self.max_depth {
             return Err(Error::DepthLimitExceeded);
         }
 
+        let start = self.cursor.position() as usize;
         let code = self.r_u8()?;
 
-        let flag = (code & Kind::FlagRef as u8) != 0; // Check if the object is a reference (FlagRef)
+        let flag = (code & Kind::FlagRef as u8) != 0;
 
         let obj_kind = Kind::from_u8(code & !(Kind::FlagRef as u8)).ok_or(Error::UnreadableKind)?;
 
-        let mut idx: Option<usize> = match obj_kind {
-            Kind::SmallTuple
-            | Kind::Tuple
-            | Kind::List
-            | Kind::Dict
-            | Kind::Set
-            | Kind::FrozenSet
-            | Kind::Code
-                if flag =>
-            {
-                self.references.push(Object::None);
-                Some(self.references.len() - 1)
+        let idx = match flag && reserves_reference(obj_kind) {
+            true => Some(self.reserve_reference()?),
+            false => None,
+        };
+
+        match obj_kind {
+            Kind::Null => {
+                self.depth -= 1;
+                return Ok(false);
+            }
+            Kind::None | Kind::Ellipsis | Kind::False | Kind::True | Kind::StopIteration => {
+                self.depth -= 1;
+                return Ok(true);
+            }
+            Kind::Ref => {
+                self.skip_bytes(4)?;
+                self.depth -= 1;
+                return Ok(true);
+            }
+            Kind::Int => self.skip_bytes(4)?,
+            Kind::Int64 | Kind::BinaryFloat => self.skip_bytes(8)?,
+            Kind::BinaryComplex => self.skip_bytes(16)?,
+            Kind::Long => {
+                let n = self.r_long()?;
+                self.skip_bytes((n.unsigned_abs() as usize).saturating_mul(2))?;
             }
-            _ => None,
+            Kind::Float => {
+                let n = self.r_u8()?;
+                self.skip_bytes(n as usize)?;
+            }
+            Kind::Complex => {
+                for _ in 0..2 {
+                    let n = self.r_u8()?;
+                    self.skip_bytes(n as usize)?;
+                }
+            }
+            Kind::String | Kind::ASCIIInterned | Kind::ASCII | Kind::Interned | Kind::Unicode => {
+                let length = self.r_long()?;
+                self.skip_bytes(length as usize)?;
+            }
+            Kind::ShortAsciiInterned | Kind::ShortAscii => {
+                let length = self.r_u8()?;
+                self.skip_bytes(length as usize)?;
+            }
+            Kind::SmallTuple => {
+                let length = self.r_u8()?;
+                for _ in 0..length {
+                    if !self.skip_object()? {
+                        return Err(Error::NullInTuple);
+                    }
+                }
+            }
+            Kind::Tuple | Kind::List | Kind::Set | Kind::FrozenSet => {
+                let length = self.r_long()?;
+                for _ in 0..length {
+                    if !self.skip_object()? {
+                        return Err(Error::NullInTuple);
+                    }
+                }
+            }
+            Kind::Dict => {
+                // Keys and values until the NULL terminator
+                while self.skip_object()? && self.skip_object()? {}
+            }
+            Kind::Code => {
+                // (longs before the objects, objects, longs after the objects, objects at the end)
+                let layout = match self.version {
+                    PyVersion {
+                        major: 3,
+                        minor: 0..=7,
+                        ..
+                    } => (5, 8, 1),
+                    PyVersion {
+                        major: 3,
+                        minor: 10,
+                        ..
+                    } => (6, 8, 1),
+                    PyVersion {
+                        major: 3,
+                        minor: 11..=13,
+                        ..
+                    } => (5, 8, 2),
+                    _ => return Err(Error::UnsupportedPyVersion(self.version)),
+                };
+
+                self.skip_bytes(layout.0 * 4)?;
+                for _ in 0..layout.1 {
+                    if !self.skip_object()? {
+                        return Err(Error::UnexpectedNull);
+                    }
+                }
+                self.skip_bytes(4)?; // firstlineno
+                for _ in 0..layout.2 {
+                    if !self.skip_object()? {
+                        return Err(Error::UnexpectedNull);
+                    }
+                }
+            }
+            Kind::Unknown | Kind::FlagRef => return Err(Error::InvalidKind(obj_kind)),
+        }
+
+        if flag {
+            let placeholder = Object::Raw {
+                start,
+                len: self.cursor.position() as usize - start,
+            };
+
+            match idx {
+                Some(index) => self.set_reference(index, placeholder),
+                None => {
+                    self.push_reference(placeholder)?;
+                }
+            }
+        }
+
+        self.depth -= 1;
+
+        Ok(true)
+    }
+
+    fn r_object(&mut self) -> Result<Option<Object>, Error> {
+        self.depth += 1;
+
+        if self.depth > self.max_depth {
+            return Err(Error::DepthLimitExceeded);
+        }
+
+        let offset = self.cursor.position();
+        let code = self.r_u8()?;
+
+        let flag = (code & Kind::FlagRef as u8) != 0; // Check if the object is a reference (FlagRef)
+
+        let Some(obj_kind) = Kind::from_u8(code & !(Kind::FlagRef as u8)) else {
+            self.depth -= 1;
+            return self.recover(offset, Error::UnreadableKind).map(Some);
+        };
+
+        self.min_marshal_version = self
+            .min_marshal_version
+            .max(kind_marshal_version(obj_kind, flag));
+
+        let mut idx: Option<usize> = match flag && reserves_reference(obj_kind) {
+            true => Some(self.reserve_reference()?),
+            false => None,
         }; // Precalculate the index for reference storage if needed
 
         let obj = match obj_kind {
@@ -302,7 +738,7 @@ impl PyReader {
                     for i in 0..size {
                         let digit = self.r_u16()?;
 
-                        if digit > (1 << 15) {
+                        if digit >= (1 << 15) {
                             return Err(Error::DigitOutOfRange(digit));
                         }
 
@@ -404,47 +840,70 @@ impl PyReader {
             }
             Kind::Set => {
                 let length = self.r_long()?;
-                let value = self
-                    .r_vec(length as usize, Kind::Set)?
-                    .into_iter()
-                    .map(
-                        // Try to convert the object to an ObjectHashable
-                        |o| match ObjectHashable::from_ref(o.clone(), &self.references) {
-                            Ok(obj) => Ok(obj),
-                            Err(_) => Err(Error::UnexpectedObject),
-                        },
-                    )
-                    .collect::<Result<IndexSet<_>, _>>()?;
+                let value = self.r_set(length as usize, Kind::Set, offset)?;
 
+                // Stored in the reserved reference slot below, like the other containers
                 let value = Object::Set(value);
 
-                if flag {
-                    let index = self.references.len();
-                    idx = Some(index);
-                    self.set_reference(idx.ok_or(Error::InvalidReference(index))?, value.clone());
-                }
-
                 Some(value)
             }
             Kind::FrozenSet => {
                 let length = self.r_long()?;
-                let value = Object::FrozenSet(
-                    self.r_vec(length as usize, Kind::FrozenSet)?
-                        .into_iter()
-                        .map(
-                            // Try to convert the object to an ObjectHashable
-                            |o| match ObjectHashable::from_ref(o.clone(), &self.references) {
-                                Ok(obj) => Ok(obj),
-                                Err(_) => Err(Error::UnexpectedObject),
-                            },
-                        )
-                        .collect::<Result<IndexSet<_>, _>>()?,
-                );
+                let value =
+                    Object::FrozenSet(self.r_set(length as usize, Kind::FrozenSet, offset)?);
 
                 Some(value)
             }
             Kind::Code => {
                 let value = match self.version {
+                    PyVersion {
+                        major: 3,
+                        minor: 0..=7,
+                        ..
+                    } => {
+                        let argcount = self.r_long()?;
+                        let kwonlyargcount = self.r_long()?;
+                        let nlocals = self.r_long()?;
+                        let stacksize = self.r_long()?;
+                        let flags = CodeFlags::from_bits_retain(self.r_long()? as u32);
+                        let code = self.r_code_body()?.into();
+                        let consts = self.r_code_body()?.into();
+                        let names = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
+                        let varnames = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
+                        let freevars = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
+                        let cellvars = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
+                        let filename = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
+                        let name = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
+                        let firstlineno = self.r_long()?;
+                        let lnotab = self.r_code_body()?.into();
+
+                        let code = code_objects::Code30 {
+                            argcount: argcount.try_into().map_err(|_| Error::InvalidConversion)?,
+                            kwonlyargcount: kwonlyargcount
+                                .try_into()
+                                .map_err(|_| Error::InvalidConversion)?,
+                            nlocals: nlocals.try_into().map_err(|_| Error::InvalidConversion)?,
+                            stacksize: stacksize
+                                .try_into()
+                                .map_err(|_| Error::InvalidConversion)?,
+                            flags,
+                            code,
+                            consts,
+                            names,
+                            varnames,
+                            freevars,
+                            cellvars,
+                            filename,
+                            name,
+                            firstlineno: firstlineno
+                                .try_into()
+                                .map_err(|_| Error::InvalidConversion)?,
+                            lnotab,
+                        };
+                        code.check_fields(&self.references, self.lazy_code)?;
+
+                        Object::Code(Code::V30(code))
+                    }
                     PyVersion {
                         major: 3,
                         minor: 10,
@@ -456,8 +915,8 @@ impl PyReader {
                         let nlocals = self.r_long()?;
                         let stacksize = self.r_long()?;
                         let flags = CodeFlags::from_bits_retain(self.r_long()? as u32);
-                        let code = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
-                        let consts = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
+                        let code = self.r_code_body()?.into();
+                        let consts = self.r_code_body()?.into();
                         let names = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
 
                         let varnames = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
@@ -472,18 +931,20 @@ impl PyReader {
 
                         let firstlineno = self.r_long()?;
 
-                        let linetable = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
+                        let linetable = self.r_code_body()?.into();
 
-                        Object::Code(Code::V310(code_objects::Code310::new(
-                            argcount.try_into().map_err(|_| Error::InvalidConversion)?,
-                            posonlyargcount
+                        let code = code_objects::Code310 {
+                            argcount: argcount.try_into().map_err(|_| Error::InvalidConversion)?,
+                            posonlyargcount: posonlyargcount
                                 .try_into()
                                 .map_err(|_| Error::InvalidConversion)?,
-                            kwonlyargcount
+                            kwonlyargcount: kwonlyargcount
+                                .try_into()
+                                .map_err(|_| Error::InvalidConversion)?,
+                            nlocals: nlocals.try_into().map_err(|_| Error::InvalidConversion)?,
+                            stacksize: stacksize
                                 .try_into()
                                 .map_err(|_| Error::InvalidConversion)?,
-                            nlocals.try_into().map_err(|_| Error::InvalidConversion)?,
-                            stacksize.try_into().map_err(|_| Error::InvalidConversion)?,
                             flags,
                             code,
                             consts,
@@ -493,12 +954,14 @@ impl PyReader {
                             cellvars,
                             filename,
                             name,
-                            firstlineno
+                            firstlineno: firstlineno
                                 .try_into()
                                 .map_err(|_| Error::InvalidConversion)?,
                             linetable,
-                            &self.references,
-                        )?))
+                        };
+                        code.check_fields(&self.references, self.lazy_code)?;
+
+                        Object::Code(Code::V310(code))
                     }
                     PyVersion {
                         major: 3,
@@ -521,8 +984,8 @@ impl PyReader {
                         let kwonlyargcount = self.r_long()?;
                         let stacksize = self.r_long()?;
                         let flags = CodeFlags::from_bits_retain(self.r_long()? as u32);
-                        let code = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
-                        let consts = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
+                        let code = self.r_code_body()?.into();
+                        let consts = self.r_code_body()?.into();
                         let names = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
                         let localsplusnames = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
                         let localspluskinds = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
@@ -530,7 +993,7 @@ impl PyReader {
                         let name = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
                         let qualname = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
                         let firstlineno = self.r_long()?;
-                        let linetable = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
+                        let linetable = self.r_code_body()?.into();
                         let exceptiontable = self.r_object()?.ok_or(Error::UnexpectedNull)?.into();
 
                         
@@ -540,89 +1003,113 @@ impl PyReader {
                                 major: 3,
                                 minor: 11,
                                 ..
-                            } => Object::Code(Code::V311(code_objects::Code311::new(
-                                argcount.try_into().map_err(|_| Error::InvalidConversion)?,
-                                posonlyargcount
-                                    .try_into()
-                                    .map_err(|_| Error::InvalidConversion)?,
-                                kwonlyargcount
-                                    .try_into()
-                                    .map_err(|_| Error::InvalidConversion)?,
-                                stacksize.try_into().map_err(|_| Error::InvalidConversion)?,
-                                flags,
-                                code,
-                                consts,
-                                names,
-                                localsplusnames,
-                                localspluskinds,
-                                filename,
-                                name,
-                                qualname,
-                                firstlineno
-                                    .try_into()
-                                    .map_err(|_| Error::InvalidConversion)?,
-                                linetable,
-                                exceptiontable,
-                                &self.references,
-                            )?)),
+                            } => {
+                                let code = code_objects::Code311 {
+                                    argcount: argcount
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    posonlyargcount: posonlyargcount
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    kwonlyargcount: kwonlyargcount
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    stacksize: stacksize
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    flags,
+                                    code,
+                                    consts,
+                                    names,
+                                    localsplusnames,
+                                    localspluskinds,
+                                    filename,
+                                    name,
+                                    qualname,
+                                    firstlineno: firstlineno
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    linetable,
+                                    exceptiontable,
+                                };
+                                code.check_fields(&self.references, self.lazy_code)?;
+
+                                Object::Code(Code::V311(code))
+                            }
                             PyVersion {
                                 major: 3,
                                 minor: 12,
                                 ..
-                            } => Object::Code(Code::V312(code_objects::Code312::new(
-                                argcount.try_into().map_err(|_| Error::InvalidConversion)?,
-                                posonlyargcount
-                                    .try_into()
-                                    .map_err(|_| Error::InvalidConversion)?,
-                                kwonlyargcount
-                                    .try_into()
-                                    .map_err(|_| Error::InvalidConversion)?,
-                                stacksize.try_into().map_err(|_| Error::InvalidConversion)?,
-                                flags,
-                                code,
-                                consts,
-                                names,
-                                localsplusnames,
-                                localspluskinds,
-                                filename,
-                                name,
-                                qualname,
-                                firstlineno
-                                    .try_into()
-                                    .map_err(|_| Error::InvalidConversion)?,
-                                linetable,
-                                exceptiontable,
-                                &self.references,
-                            )?)),
+                            } => {
+                                let code = code_objects::Code312 {
+                                    argcount: argcount
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    posonlyargcount: posonlyargcount
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    kwonlyargcount: kwonlyargcount
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    stacksize: stacksize
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    flags,
+                                    code,
+                                    consts,
+                                    names,
+                                    localsplusnames,
+                                    localspluskinds,
+                                    filename,
+                                    name,
+                                    qualname,
+                                    firstlineno: firstlineno
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    linetable,
+                                    exceptiontable,
+                                };
+                                code.check_fields(&self.references, self.lazy_code)?;
+
+                                Object::Code(Code::V312(code))
+                            }
                             PyVersion {
                                 major: 3,
                                 minor: 13,
                                 ..
-                            } => Object::Code(Code::V313(code_objects::Code313::new(
-                                argcount.try_into().map_err(|_| Error::InvalidConversion)?,
-                                posonlyargcount
-                                    .try_into()
-                                    .map_err(|_| Error::InvalidConversion)?,
-                                kwonlyargcount
-                                    .try_into()
-                                    .map_err(|_| Error::InvalidConversion)?,
-                                stacksize.try_into().map_err(|_| Error::InvalidConversion)?,
-                                flags,
-                                code,
-                                consts,
-                                names,
-                                localsplusnames,
-                                localspluskinds,
-                                filename,
-                                name,
-                                qualname,
-                                firstlineno
-                                    .try_into()
-                                    .map_err(|_| Error::InvalidConversion)?,
-                                linetable,
-                                exceptiontable,
-                                &self.references,
-                            )?)),
+                            } => {
+                                let code = code_objects::Code313 {
+                                    argcount: argcount
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    posonlyargcount: posonlyargcount
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    kwonlyargcount: kwonlyargcount
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    stacksize: stacksize
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    flags,
+                                    code,
+                                    consts,
+                                    names,
+                                    localsplusnames,
+                                    localspluskinds,
+                                    filename,
+                                    name,
+                                    qualname,
+                                    firstlineno: firstlineno
+                                        .try_into()
+                                        .map_err(|_| Error::InvalidConversion)?,
+                                    linetable,
+                                    exceptiontable,
+                                };
+                                code.check_fields(&self.references, self.lazy_code)?;
+
+                                Object::Code(Code::V313(code))
+                            }
                             _ => unreachable!(),
                         }
                     }
@@ -639,29 +1126,33 @@ impl PyReader {
                 let reference = self.references.get(index);
 
                 match reference {
-                    Some(_) => Some(Object::LoadRef(index)),
-                    None => return Err(Error::InvalidReference(index)),
+                    // A reserved slot that is still being read is a recursive reference, which resolves once the container is complete
+                    Some(_) if self.orphaned.contains(&index) => {
+                        let stored = self.stored_indices(&Object::StoreRef(index));
+                        self.orphaned.retain(|index| !stored.contains(index));
+
+                        Some(Object::StoreRef(index))
+                    }
+                    Some(_) if !self.dangling.contains(&index) => Some(Object::LoadRef(index)),
+                    _ => Some(self.recover(offset, Error::InvalidReference(index))?),
                 }
             }
-            Kind::Unknown => return Err(Error::InvalidKind(obj_kind)),
-            Kind::StopIteration | Kind::FlagRef => todo!(),
+            Kind::Unknown => Some(self.recover(offset, Error::InvalidKind(obj_kind))?),
+            Kind::StopIteration => Some(Object::StopIteration),
+            // The flag is masked out above, so it can't be the kind itself
+            Kind::FlagRef => return Err(Error::InvalidKind(obj_kind)),
         };
 
         match (&obj, idx) {
-            (None, _)
-            | (Some(Object::None), _)
-            | (Some(Object::StopIteration), _)
-            | (Some(Object::Ellipsis), _)
-            | (Some(Object::Bool(_)), _)
-            | (Some(Object::LoadRef(_)), _) => {}
+            (None, _) => {}
+            // CPython ignores the flag on these, see `Object::is_ref_eligible`
+            (Some(x), _) if !x.is_ref_eligible() => {}
             (Some(x), Some(i)) if flag => {
                 idx = Some(i);
                 self.set_reference(i, x.clone());
             }
             (Some(x), None) if flag => {
-                idx = Some(self.references.len());
-
-                self.references.push(x.clone());
+                idx = Some(self.push_reference(x.clone())?);
             }
             (Some(_), _) => {}
         };
@@ -669,18 +1160,77 @@ impl PyReader {
         self.depth -= 1;
 
         match flag {
+            // Older versions of this crate wrote the dict terminator with FLAG_REF set, a NULL can never be a reference so ignore the flag
+            true if obj.is_none() => Ok(None),
             true => Ok(Some(Object::StoreRef(idx.ok_or(Error::InvalidStoreRef)?))),
             false => Ok(obj),
         }
     }
 
     pub fn read_object(&mut self) -> Result<Object, Error> {
-        if self.cursor.position() == self.cursor.get_ref().len() as u64 {
-            panic!("EOF, don't know what to do");
+        if self.cursor.position() >= self.cursor.get_ref().len() as u64 {
+            return Err(Error::InvalidData(std::io::Error::from(
+                std::io::ErrorKind::UnexpectedEof,
+            )));
         }
 
-        let object = self.r_object()?;
+        let object = match self.r_object() {
+            Ok(object) => object,
+            Err(err) => {
+                // The placeholders of the containers that were being read stay in the table, don't let a later object resolve to them
+                self.dangling.extend(self.reserved.drain(..));
+                self.depth = 0;
+
+                return Err(err);
+            }
+        };
 
         object.ok_or(Error::UnexpectedObject)
     }
 }
+
+/// Decodes an `Object::Raw` range of `data` that was skipped by a lazy reader. References stored inside the range were numbered in stream order,
+/// so they form one block in the reference table starting at the first placeholder inside the range. Reading the range again with the table cut off at that point
+/// gives every reference its original index. Nested code objects are read lazily as well.
+/// Returns the decoded object, the references read from the range and the index of the first one.
+pub(crate) fn read_raw(
+    data: &[u8],
+    start: usize,
+    len: usize,
+    version: PyVersion,
+    references: &mut [Object],
+) -> Result<(Object, Vec<Object>, usize), Error> {
+    let end = start + len;
+    let data = data
+        .get(..end)
+        .ok_or(Error::InvalidData(std::io::Error::from(
+            std::io::ErrorKind::UnexpectedEof,
+        )))?;
+
+    let first_ref = references
+        .iter()
+        .position(|r| matches!(r, Object::Raw { start: s, .. } if (start..end).contains(s)))
+        .unwrap_or(references.len());
+
+    // The reader needs the references before the range, move them in instead of cloning the table for every range and move them back after
+    let prefix = references[..first_ref]
+        .iter_mut()
+        .map(|reference| std::mem::replace(reference, Object::None))
+        .collect();
+
+    let mut reader =
+        PyReader::at_position(data, version, start as u64, prefix).with_lazy_code(true);
+
+    let object = reader.r_object();
+    let decoded_refs = reader.references.split_off(first_ref);
+
+    for (slot, reference) in references.iter_mut().zip(reader.references) {
+        *slot = reference;
+    }
+
+    Ok((
+        object?.ok_or(Error::UnexpectedNull)?,
+        decoded_refs,
+        first_ref,
+    ))
+}
@@ -1,5 +1,9 @@
+use std::collections::HashSet;
+
 use crate::{
-    Object, ObjectHashable, optimize_references,
+    Code, Object, ObjectHashable,
+    error::Error,
+    optimize_references,
     optimizer::{Transformable, Transformer},
 };
 
@@ -9,6 +13,10 @@ struct RecursiveCheck<'a> {
     recursive_refs: Vec<usize>,
     /// Stack to keep track of the current references being visited.
     ref_stack: Vec<usize>,
+    /// Every distinct cycle found, as the references on the stack from the repeated one up to and including it again.
+    cycles: Vec<Vec<usize>>,
+    /// The first reference that can't be resolved at all, either because it's out of bounds or a StoreRef that contains itself.
+    error: Option<Error>,
 }
 
 impl<'a> RecursiveCheck<'a> {
@@ -17,6 +25,49 @@ impl<'a> RecursiveCheck<'a> {
             references,
             recursive_refs: Vec::new(),
             ref_stack: Vec::new(),
+            cycles: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Visits the referenced object, unless the reference is already on the stack.
+    fn check(&mut self, index: usize, is_store: bool) {
+        if self.error.is_some() {
+            return;
+        }
+
+        if self.ref_stack.contains(&index) {
+            // A LoadRef to a reference that is being stored is a regular recursive reference,
+            // but a StoreRef would have to contain a complete copy of itself
+            match is_store {
+                true => self.error = Some(Error::RecursiveReference),
+                false => self.found_cycle(index),
+            }
+
+            return;
+        }
+
+        let Some(reference) = self.references.get(index) else {
+            self.error = Some(Error::InvalidReference(index));
+            return;
+        };
+
+        self.ref_stack.push(index);
+        let mut obj = reference.clone();
+        self.visit(&mut obj);
+        self.ref_stack.pop();
+    }
+
+    fn found_cycle(&mut self, index: usize) {
+        self.recursive_refs.push(index);
+
+        if let Some(start) = self.ref_stack.iter().position(|i| *i == index) {
+            let mut cycle = self.ref_stack[start..].to_vec();
+            cycle.push(index);
+
+            if !self.cycles.contains(&cycle) {
+                self.cycles.push(cycle);
+            }
         }
     }
 }
@@ -24,14 +75,7 @@ impl<'a> RecursiveCheck<'a> {
 impl Transformer for RecursiveCheck<'_> {
     fn visit_LoadRef(&mut self, obj: &mut Object) -> Option<Object> {
         if let Object::LoadRef(index) = obj {
-            if self.ref_stack.contains(index) {
-                self.recursive_refs.push(*index);
-            } else {
-                self.ref_stack.push(*index);
-                let mut obj = self.references[*index].clone();
-                self.visit(&mut obj);
-                self.ref_stack.pop();
-            }
+            self.check(*index, false);
         }
 
         None
@@ -39,14 +83,7 @@ impl Transformer for RecursiveCheck<'_> {
 
     fn visit_StoreRef(&mut self, obj: &mut Object) -> Option<Object> {
         if let Object::StoreRef(index) = obj {
-            if !self.ref_stack.contains(index) {
-                self.ref_stack.push(*index);
-                let mut obj = self.references[*index].clone();
-                self.visit(&mut obj);
-                self.ref_stack.pop();
-            } else {
-                panic!("Recursive reference in StoreRef");
-            }
+            self.check(*index, true);
         }
 
         None
@@ -54,14 +91,7 @@ impl Transformer for RecursiveCheck<'_> {
 
     fn visit_HashableLoadRef(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
         if let ObjectHashable::LoadRef(index) = obj {
-            if self.ref_stack.contains(index) {
-                self.recursive_refs.push(*index);
-            } else {
-                self.ref_stack.push(*index);
-                let mut obj = self.references[*index].clone();
-                self.visit(&mut obj);
-                self.ref_stack.pop();
-            }
+            self.check(*index, false);
         }
 
         None
@@ -69,14 +99,7 @@ impl Transformer for RecursiveCheck<'_> {
 
     fn visit_HashableStoreRef(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
         if let ObjectHashable::StoreRef(index) = obj {
-            if !self.ref_stack.contains(index) {
-                self.ref_stack.push(*index);
-                let mut obj = self.references[*index].clone();
-                self.visit(&mut obj);
-                self.ref_stack.pop();
-            } else {
-                panic!("Recursive reference in StoreRef");
-            }
+            self.check(*index, true);
         }
 
         None
@@ -159,6 +182,31 @@ pub fn get_recursive_refs(obj: &Object, references: &[Object]) -> Vec<usize> {
     checker.recursive_refs
 }
 
+/// Returns every reference cycle in the given object and its references, as the path of reference indices that leads back
+/// to the first one, e.g. `[0, 3, 0]` when reference 0 contains reference 3 which loads reference 0 again.
+/// A cycle that is reached multiple times is only returned once.
+pub fn find_reference_cycles(obj: &Object, references: &[Object]) -> Vec<Vec<usize>> {
+    let mut checker = RecursiveCheck::new(references);
+
+    let mut obj = obj.clone();
+
+    obj.transform(&mut checker);
+
+    checker.cycles
+}
+
+/// Checks that `resolve_all_refs` can be used on the object: every reference has to be in bounds and no StoreRef can contain itself.
+/// Returns `Error::InvalidReference` or `Error::RecursiveReference` otherwise. Recursive LoadRefs are fine, they're left as is.
+pub(crate) fn check_resolvable(obj: &Object, references: &[Object]) -> Result<(), Error> {
+    let mut checker = RecursiveCheck::new(references);
+
+    let mut obj = obj.clone();
+
+    obj.transform(&mut checker);
+
+    checker.error.map_or(Ok(()), Err)
+}
+
 /// Attempts to resolve all references in the given object and its references. This will remove all unused references and resolve all non-recursively stored references.
 /// If there are any recursive references, they will be left as LoadRef or StoreRef objects and included in the returned references.
 pub fn resolve_all_refs(obj: &Object, references: &[Object]) -> (Object, Vec<Object>) {
@@ -177,3 +225,241 @@ pub fn resolve_all_refs(obj: &Object, references: &[Object]) -> (Object, Vec<Obj
 
     (obj, resolved_refs)
 }
+
+/// Collects the indices of all references directly contained in the object, including the ones in dict keys.
+fn collect_references(obj: &Object, found: &mut Vec<usize>) {
+    obj.walk(&mut |obj| match obj {
+        Object::LoadRef(index) | Object::StoreRef(index) => found.push(*index),
+        Object::Dict(dict) => {
+            for key in dict.keys() {
+                collect_references(&Object::from(key.clone()), found);
+            }
+        }
+        _ => {}
+    });
+}
+
+/// Returns the indices of all references reachable from the given object, following both LoadRef and StoreRef into the referenced objects.
+/// Unlike `get_used_references`, which only counts LoadRef usages, this is the full set of references the object depends on.
+pub fn reachable_references(obj: &Object, references: &[Object]) -> HashSet<usize> {
+    let mut reachable = HashSet::new();
+    let mut pending = Vec::new();
+
+    collect_references(obj, &mut pending);
+
+    while let Some(index) = pending.pop() {
+        if reachable.insert(index)
+            && let Some(reference) = references.get(index)
+        {
+            collect_references(reference, &mut pending);
+        }
+    }
+
+    reachable
+}
+
+/// A read-only view of an object that resolves references lazily by borrowing from the reference table instead of cloning it.
+/// Useful for analysis where the object is only traversed and never dumped again.
+#[derive(Clone, Copy, Debug)]
+pub struct ResolvedView<'a> {
+    object: &'a Object,
+    references: &'a [Object],
+}
+
+impl<'a> ResolvedView<'a> {
+    /// Creates a view of the given object, following LoadRef and StoreRef until a concrete object is reached.
+    pub fn new(object: &'a Object, references: &'a [Object]) -> Result<Self, Error> {
+        let mut object = object;
+        let mut hops = 0;
+
+        while let Object::LoadRef(index) | Object::StoreRef(index) = object {
+            // A chain longer than the reference table can only be a reference pointing to itself
+            if hops > references.len() {
+                return Err(Error::InvalidReference(*index));
+            }
+
+            object = references
+                .get(*index)
+                .ok_or(Error::InvalidReference(*index))?;
+            hops += 1;
+        }
+
+        Ok(Self { object, references })
+    }
+
+    /// Returns the resolved object. This is never a LoadRef or StoreRef.
+    pub fn object(&self) -> &'a Object {
+        self.object
+    }
+
+    pub fn references(&self) -> &'a [Object] {
+        self.references
+    }
+
+    /// Creates a view of a child object using the same reference table.
+    pub fn view(&self, object: &'a Object) -> Result<Self, Error> {
+        Self::new(object, self.references)
+    }
+
+    /// Creates a view of a hashable child object. Returns `None` if the object is not a reference, in which case it can be used as is.
+    pub fn view_hashable(&self, object: &'a ObjectHashable) -> Result<Option<Self>, Error> {
+        match object {
+            ObjectHashable::LoadRef(index) | ObjectHashable::StoreRef(index) => {
+                let resolved = self
+                    .references
+                    .get(*index)
+                    .ok_or(Error::InvalidReference(*index))?;
+
+                Ok(Some(self.view(resolved)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns views of the elements of a tuple or list.
+    pub fn elements(&self) -> Result<Vec<Self>, Error> {
+        match self.object {
+            Object::Tuple(elems) | Object::List(elems) => {
+                elems.iter().map(|o| self.view(o)).collect()
+            }
+            _ => Err(Error::UnexpectedObject),
+        }
+    }
+
+    /// Returns views of the values of a dict, paired with their keys.
+    pub fn items(&self) -> Result<Vec<(&'a ObjectHashable, Self)>, Error> {
+        match self.object {
+            Object::Dict(dict) => dict.iter().map(|(k, v)| Ok((k, self.view(v)?))).collect(),
+            _ => Err(Error::UnexpectedObject),
+        }
+    }
+
+    /// Returns the code object if the resolved object is one. Its fields can be viewed with `view`.
+    pub fn code(&self) -> Option<&'a Code> {
+        match self.object {
+            Object::Code(code) => Some(code),
+            _ => None,
+        }
+    }
+}
+
+/// Replaces every LoadRef and StoreRef with a copy of the referenced object. Only use this on objects without recursive references.
+struct Inliner<'a> {
+    references: &'a [Object],
+}
+
+impl Inliner<'_> {
+    fn resolve(&mut self, index: usize) -> Option<Object> {
+        let mut obj = self.references.get(index)?.clone();
+        obj.transform(self);
+
+        Some(obj)
+    }
+}
+
+impl Transformer for Inliner<'_> {
+    fn visit_LoadRef(&mut self, obj: &mut Object) -> Option<Object> {
+        match obj {
+            Object::LoadRef(index) => self.resolve(*index),
+            _ => None,
+        }
+    }
+
+    fn visit_StoreRef(&mut self, obj: &mut Object) -> Option<Object> {
+        match obj {
+            Object::StoreRef(index) => self.resolve(*index),
+            _ => None,
+        }
+    }
+
+    fn visit_HashableLoadRef(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
+        match obj {
+            ObjectHashable::LoadRef(index) => ObjectHashable::try_from(self.resolve(*index)?).ok(),
+            _ => None,
+        }
+    }
+
+    fn visit_HashableStoreRef(&mut self, obj: &mut ObjectHashable) -> Option<ObjectHashable> {
+        match obj {
+            ObjectHashable::StoreRef(index) => ObjectHashable::try_from(self.resolve(*index)?).ok(),
+            _ => None,
+        }
+    }
+}
+
+/// Resolves a single field of a code object and stores the result inline. Fields that contain recursive references are left as is.
+fn inline_field(field: &mut Object, references: &[Object]) -> Result<(), Error> {
+    // Make sure every reference in the field exists and none of them stores itself before resolving it
+    check_resolvable(field, references)?;
+
+    if get_recursive_refs(field, references).is_empty() {
+        field.transform(&mut Inliner { references });
+    }
+
+    Ok(())
+}
+
+/// Inlines all object fields of a Code31x object (Python 3.11, 3.12, 3.13) which share the same structure
+macro_rules! inline_code311_fields {
+    ($code:ident, $references:ident) => {
+        for field in [
+            &mut $code.code,
+            &mut $code.consts,
+            &mut $code.names,
+            &mut $code.localsplusnames,
+            &mut $code.localspluskinds,
+            &mut $code.filename,
+            &mut $code.name,
+            &mut $code.qualname,
+            &mut $code.linetable,
+            &mut $code.exceptiontable,
+        ] {
+            inline_field(field, $references)?;
+        }
+    };
+}
+
+impl Code {
+    /// Resolves every object field of the code object to its inline value, so fields can be matched on directly without `resolve_object_ref!`.
+    /// This is `resolve_all_refs` scoped to the fields of one code object. Fields containing recursive references are left unchanged.
+    /// Returns `Error::InvalidReference` or `Error::RecursiveReference` if a field has a reference that can't be resolved at all.
+    pub fn inline_fields(&mut self, references: &[Object]) -> Result<(), Error> {
+        match self {
+            Code::V30(code) => {
+                for field in [
+                    &mut code.code,
+                    &mut code.consts,
+                    &mut code.names,
+                    &mut code.varnames,
+                    &mut code.freevars,
+                    &mut code.cellvars,
+                    &mut code.filename,
+                    &mut code.name,
+                    &mut code.lnotab,
+                ] {
+                    inline_field(field, references)?;
+                }
+            }
+            Code::V310(code) => {
+                for field in [
+                    &mut code.code,
+                    &mut code.consts,
+                    &mut code.names,
+                    &mut code.varnames,
+                    &mut code.freevars,
+                    &mut code.cellvars,
+                    &mut code.filename,
+                    &mut code.name,
+                    &mut code.linetable,
+                ] {
+                    inline_field(field, references)?;
+                }
+            }
+            Code::V311(code) => inline_code311_fields!(code, references),
+            Code::V312(code) => inline_code311_fields!(code, references),
+            Code::V313(code) => inline_code311_fields!(code, references),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+//! SipHash as used by CPython's `_Py_KeyedHash` to hash the source of hash-based pyc files, see
+//! https://github.com/python/cpython/blob/main/Python/pyhash.c
+
+struct State {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+}
+
+impl State {
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13) ^ self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16) ^ self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21) ^ self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17) ^ self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    fn compress(&mut self, word: u64, rounds: usize) {
+        self.v3 ^= word;
+        for _ in 0..rounds {
+            self.round();
+        }
+        self.v0 ^= word;
+    }
+}
+
+/// Hashes `data` with SipHash-`c`-`d` (`c` compression and `d` finalization rounds), using `k0` and `k1` as the key.
+pub(crate) fn siphash(k0: u64, k1: u64, data: &[u8], c: usize, d: usize) -> u64 {
+    let mut state = State {
+        v0: k0 ^ 0x736f6d6570736575,
+        v1: k1 ^ 0x646f72616e646f6d,
+        v2: k0 ^ 0x6c7967656e657261,
+        v3: k1 ^ 0x7465646279746573,
+    };
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let word = u64::from_le_bytes(chunk.try_into().expect("chunks are 8 bytes"));
+        state.compress(word, c);
+    }
+
+    // The last word holds the remaining bytes and the length of the data in the most significant byte
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    let word = u64::from_le_bytes(last) | ((data.len() as u64) << 56);
+    state.compress(word, c);
+
+    state.v2 ^= 0xff;
+    for _ in 0..d {
+        state.round();
+    }
+
+    state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+}
@@ -1,3 +1,5 @@
+use std::collections::{HashMap, HashSet};
+
 use bstr::BString;
 use num_bigint::BigInt;
 use num_complex::Complex;
@@ -35,11 +37,7 @@ macro_rules! w_code311 {
                 .map_err(|_| Error::InvalidConversion)?,
         );
         $self.w_long(
-            $value
-                .flags
-                .bits()
-                .try_into()
-                .map_err(|_| Error::InvalidConversion)?,
+            $value.flags.bits() as i32, // Stored as a C int, like the reader
         );
         $self.w_object(Some((*$value.code).clone()), false)?;
         $self.w_object(Some((*$value.consts).clone()), false)?;
@@ -60,6 +58,30 @@ macro_rules! w_code311 {
     };
 }
 
+/// Lists the serialized field sizes of Code31x objects (Python 3.11, 3.12, 3.13) which share the same structure
+macro_rules! code311_field_sizes {
+    ($value:ident, $size:ident) => {
+        vec![
+            ("argcount", LONG_SIZE),
+            ("posonlyargcount", LONG_SIZE),
+            ("kwonlyargcount", LONG_SIZE),
+            ("stacksize", LONG_SIZE),
+            ("flags", LONG_SIZE),
+            ("code", $size(&$value.code)?),
+            ("consts", $size(&$value.consts)?),
+            ("names", $size(&$value.names)?),
+            ("localsplusnames", $size(&$value.localsplusnames)?),
+            ("localspluskinds", $size(&$value.localspluskinds)?),
+            ("filename", $size(&$value.filename)?),
+            ("name", $size(&$value.name)?),
+            ("qualname", $size(&$value.qualname)?),
+            ("firstlineno", LONG_SIZE),
+            ("linetable", $size(&$value.linetable)?),
+            ("exceptiontable", $size(&$value.exceptiontable)?),
+        ]
+    };
+}
+
 /// On windows this is 1000.
 /// See https://github.com/python/cpython/blob/3.10/Python/marshal.c#L36
 #[cfg(windows)]
@@ -69,6 +91,244 @@ static MAX_DEPTH: usize = 1000;
 #[cfg(not(windows))]
 static MAX_DEPTH: usize = 2000;
 
+/// Formats a float the way CPython's marshal version 0 does, which is `PyOS_double_to_string(v, 'g', 17, 0, NULL)`.
+/// See https://github.com/python/cpython/blob/3.10/Python/marshal.c
+fn format_float_g17(value: f64) -> String {
+    if value.is_nan() {
+        return "nan".to_string();
+    }
+
+    if value.is_infinite() && value.is_sign_negative() {
+        return "-inf".to_string();
+    }
+
+    if value.is_infinite() {
+        return "inf".to_string();
+    }
+
+    // 17 significant digits in scientific notation, e.g. "1.0000000000000001e300"
+    let scientific = format!("{:.16e}", value);
+    let (mantissa, exponent) = scientific
+        .split_once('e')
+        .expect("scientific notation always contains an exponent");
+    let exponent: i32 = exponent.parse().expect("exponent is always an integer");
+
+    let strip_zeros = |s: &str| s.trim_end_matches('0').trim_end_matches('.').to_string();
+
+    if !(-4..17).contains(&exponent) {
+        format!(
+            "{}e{}{:02}",
+            strip_zeros(mantissa),
+            if exponent < 0 { '-' } else { '+' },
+            exponent.abs()
+        )
+    } else {
+        let fixed = format!("{:.*}", (16 - exponent) as usize, value);
+
+        if fixed.contains('.') {
+            strip_zeros(&fixed)
+        } else {
+            fixed
+        }
+    }
+}
+
+/// Collects the index of every reference in the object (including dict keys), and whether it's a `StoreRef`.
+pub(crate) fn collect_reference_indices(obj: &Object, indices: &mut Vec<(usize, bool)>) {
+    obj.walk(&mut |obj| match obj {
+        Object::LoadRef(index) => indices.push((*index, false)),
+        Object::StoreRef(index) => indices.push((*index, true)),
+        // `walk` skips dict keys
+        Object::Dict(dict) => {
+            for key in dict.keys() {
+                collect_reference_indices(&Object::from(key.clone()), indices);
+            }
+        }
+        _ => {}
+    });
+}
+
+/// See `PyWriter::validate_references`.
+pub(crate) fn check_reference_indices(obj: &Object, references: &[Object]) -> Result<(), Error> {
+    let mut stored = HashSet::new();
+    let mut pending = vec![obj];
+
+    while let Some(obj) = pending.pop() {
+        let mut indices = Vec::new();
+        collect_reference_indices(obj, &mut indices);
+
+        for (index, is_store) in indices {
+            let reference = references
+                .get(index)
+                .ok_or(Error::InvalidReference(index))?;
+
+            // The writer writes the referenced object in place of a StoreRef, so its references have to be valid too
+            if is_store && stored.insert(index) {
+                pending.push(reference);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the kind a string is written as, kinds the marshal version doesn't have fall back to the ones CPython writes instead.
+/// The ASCII kinds were added in version 4, before that strings are `Interned` (version 3) or `Unicode`. ASCII is valid UTF-8, so the value stays the same.
+/// See https://github.com/python/cpython/blob/3.13/Python/marshal.c
+fn string_kind(kind: Kind, marshal_version: u8) -> Kind {
+    match kind {
+        Kind::ASCII | Kind::ShortAscii if marshal_version < 4 => Kind::Unicode,
+        Kind::ASCIIInterned | Kind::ShortAsciiInterned | Kind::Interned if marshal_version < 3 => {
+            Kind::Unicode
+        }
+        Kind::ASCIIInterned | Kind::ShortAsciiInterned if marshal_version < 4 => Kind::Interned,
+        kind => kind,
+    }
+}
+
+/// Size of an integer field written with `w_long`
+const LONG_SIZE: usize = 4;
+
+/// Size of the fields of a Code31x object (Python 3.11, 3.12, 3.13) which share the same structure, see `object_size`
+macro_rules! code311_size {
+    ($value:ident, $size:ident) => {
+        // 5 integers before the objects and `firstlineno`
+        Ok(1 + 6 * LONG_SIZE
+            + [
+                &$value.code,
+                &$value.consts,
+                &$value.names,
+                &$value.localsplusnames,
+                &$value.localspluskinds,
+                &$value.filename,
+                &$value.name,
+                &$value.qualname,
+                &$value.linetable,
+                &$value.exceptiontable,
+            ]
+            .into_iter()
+            .map(|obj| $size(obj))
+            .sum::<Result<usize, Error>>()?)
+    };
+}
+
+/// Computes how many bytes `PyWriter::write_object` writes for the object, failing in the same cases (except for lengths that don't fit a C int).
+/// Mirrors `w_object`, so both have to be changed together.
+pub(crate) fn object_size(
+    obj: &Object,
+    references: &[Object],
+    marshal_version: u8,
+    depth: usize,
+) -> Result<usize, Error> {
+    let depth = depth + 1;
+
+    if depth > MAX_DEPTH {
+        return Err(Error::DepthLimitExceeded);
+    }
+
+    let size = |obj: &Object| object_size(obj, references, marshal_version, depth);
+    // Floats are written as a string with a one byte length before marshal version 2
+    let float_size = |value: f64| match marshal_version {
+        0 | 1 => 1 + format_float_g17(value).len(),
+        _ => 8,
+    };
+
+    match obj {
+        Object::None | Object::StopIteration | Object::Ellipsis | Object::Bool(_) => Ok(1),
+        Object::Long(num) => match num.to_i32() {
+            Some(_) => Ok(1 + LONG_SIZE),
+            // 15 bits per digit, each stored in 2 bytes
+            None => Ok(1 + LONG_SIZE + 2 * num.bits().div_ceil(15) as usize),
+        },
+        Object::Float(value) => Ok(1 + float_size(value.into_inner())),
+        Object::Complex(Complex { re, im }) => {
+            Ok(1 + float_size(re.into_inner()) + float_size(im.into_inner()))
+        }
+        Object::Bytes(value) => Ok(1 + LONG_SIZE + value.len()),
+        Object::String(value) => match string_kind(value.kind, marshal_version) {
+            Kind::ASCII | Kind::ASCIIInterned | Kind::Interned | Kind::Unicode => {
+                Ok(1 + LONG_SIZE + value.value.len())
+            }
+            Kind::ShortAscii | Kind::ShortAsciiInterned => Ok(2 + value.value.len()),
+            kind => Err(Error::InvalidKind(kind)),
+        },
+        Object::Tuple(value) => {
+            // SmallTuple was added in marshal version 4
+            let header = if marshal_version >= 4 && value.len() <= 255 {
+                2
+            } else {
+                1 + LONG_SIZE
+            };
+
+            Ok(header + value.iter().map(size).sum::<Result<usize, Error>>()?)
+        }
+        Object::List(value) => {
+            Ok(1 + LONG_SIZE + value.iter().map(size).sum::<Result<usize, Error>>()?)
+        }
+        Object::Dict(value) => {
+            let mut total = 2; // The kind and the NULL terminator
+            for (key, value) in value {
+                total += size(&Object::from(key.clone()))? + size(value)?;
+            }
+
+            Ok(total)
+        }
+        Object::Set(value) | Object::FrozenSet(value) => Ok(1
+            + LONG_SIZE
+            + value
+                .iter()
+                .map(|obj| size(&Object::from(obj.clone())))
+                .sum::<Result<usize, Error>>()?),
+        Object::Code(Code::V30(value)) => Ok(1
+            + 6 * LONG_SIZE
+            + [
+                &value.code,
+                &value.consts,
+                &value.names,
+                &value.varnames,
+                &value.freevars,
+                &value.cellvars,
+                &value.filename,
+                &value.name,
+                &value.lnotab,
+            ]
+            .into_iter()
+            .map(|obj| size(obj))
+            .sum::<Result<usize, Error>>()?),
+        Object::Code(Code::V310(value)) => Ok(1
+            + 7 * LONG_SIZE
+            + [
+                &value.code,
+                &value.consts,
+                &value.names,
+                &value.varnames,
+                &value.freevars,
+                &value.cellvars,
+                &value.filename,
+                &value.name,
+                &value.linetable,
+            ]
+            .into_iter()
+            .map(|obj| size(obj))
+            .sum::<Result<usize, Error>>()?),
+        Object::Code(Code::V311(value)) => code311_size!(value, size),
+        Object::Code(Code::V312(value)) => code311_size!(value, size),
+        Object::Code(Code::V313(value)) => code311_size!(value, size),
+        Object::LoadRef(index) => match references.get(*index) {
+            // Written inline before marshal version 3, like a StoreRef
+            Some(reference) if marshal_version < 3 => size(reference),
+            Some(_) => Ok(1 + LONG_SIZE),
+            None => Err(Error::InvalidReference(*index)),
+        },
+        // The referenced object is written in place of a StoreRef
+        Object::StoreRef(index) => match references.get(*index) {
+            Some(reference) => size(reference),
+            None => Err(Error::InvalidReference(*index)),
+        },
+        Object::Raw { .. } | Object::Unknown { .. } => Err(Error::InvalidObject(obj.clone())),
+    }
+}
+
 /// A writer for Python objects that serializes them into a binary format
 pub struct PyWriter {
     data: Vec<u8>,
@@ -76,6 +336,12 @@ pub struct PyWriter {
     references: Vec<Object>,
     /// The current depth of the object being written.
     depth: usize,
+    /// The maximum depth before writing fails, see `with_max_depth`.
+    max_depth: usize,
+    /// Maps the index of every reference written so far to the index it's read back as, references are numbered in the order they're written.
+    stored: HashMap<usize, usize>,
+    /// How many objects have been written with FLAG_REF.
+    flagged: usize,
 }
 
 impl PyWriter {
@@ -85,9 +351,27 @@ impl PyWriter {
             marshal_version,
             references,
             depth: 0,
+            max_depth: MAX_DEPTH,
+            stored: HashMap::new(),
+            flagged: 0,
+        }
+    }
+
+    /// Same as `new`, but preallocates `capacity` bytes for the output, e.g. the size of the input when dumping a loaded object again.
+    pub fn with_capacity(references: Vec<Object>, marshal_version: u8, capacity: usize) -> Self {
+        Self {
+            data: Vec::with_capacity(capacity),
+            ..Self::new(references, marshal_version)
         }
     }
 
+    /// Sets the maximum nesting depth of the objects that can be written, which defaults to the limit of CPython (2000, 1000 on Windows).
+    /// Every level of nesting is a recursive call, so a very high limit can overflow the stack.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
     fn w_u8(&mut self, value: u8) {
         self.data.push(value);
     }
@@ -96,10 +380,23 @@ impl PyWriter {
         self.data.extend_from_slice(&value.to_le_bytes());
     }
 
+    // Marshal data is little-endian on every platform, all integers and floats are written with `to_le_bytes`
     fn w_long(&mut self, value: i32) {
         self.data.extend_from_slice(&value.to_le_bytes());
     }
 
+    /// Writes a length or index as a C int, failing instead of wrapping when it doesn't fit
+    fn w_size(&mut self, size: usize) -> Result<(), Error> {
+        self.w_long(size.try_into().map_err(|_| Error::InvalidConversion)?);
+        Ok(())
+    }
+
+    /// Writes the length of a short string or small tuple as a single byte
+    fn w_short_size(&mut self, size: usize) -> Result<(), Error> {
+        self.w_u8(size.try_into().map_err(|_| Error::InvalidConversion)?);
+        Ok(())
+    }
+
     fn w_kind(&mut self, kind: Kind, is_ref: bool) {
         match is_ref {
             true => self.w_u8(kind as u8 | Kind::FlagRef as u8),
@@ -122,7 +419,11 @@ impl PyWriter {
             value >>= 15;
         }
 
-        self.w_long((digits.len() as i32) * if num.is_negative() { -1 } else { 1 });
+        let size: i32 = digits
+            .len()
+            .try_into()
+            .map_err(|_| Error::InvalidConversion)?;
+        self.w_long(if num.is_negative() { -size } else { size });
 
         for digit in digits {
             self.w_u16(digit);
@@ -131,33 +432,35 @@ impl PyWriter {
         Ok(())
     }
 
-    fn w_string(&mut self, value: &BString, as_u8: bool) {
+    fn w_string(&mut self, value: &BString, as_u8: bool) -> Result<(), Error> {
         if as_u8 {
-            self.w_u8(value.len() as u8);
+            self.w_short_size(value.len())?;
         } else {
-            self.w_long(value.len() as i32);
+            self.w_size(value.len())?;
         }
 
         self.data
             .extend_from_slice(&value.iter().copied().collect::<Vec<u8>>());
+
+        Ok(())
     }
 
     fn w_float_bin(&mut self, value: f64) {
         self.data.extend_from_slice(&value.to_le_bytes());
     }
 
-    fn w_float_str(&mut self, value: f64) {
-        self.w_string(&value.to_string().into(), true);
+    fn w_float_str(&mut self, value: f64) -> Result<(), Error> {
+        self.w_string(&format_float_g17(value).into(), true)
     }
 
     fn w_bytes(&mut self, value: &[u8]) {
         self.data.extend_from_slice(value);
     }
 
-    fn w_object(&mut self, obj: Option<Object>, is_ref: bool) -> Result<(), Error> {
+    pub(crate) fn w_object(&mut self, obj: Option<Object>, is_ref: bool) -> Result<(), Error> {
         self.depth += 1;
 
-        if self.depth > MAX_DEPTH {
+        if self.depth > self.max_depth {
             return Err(Error::DepthLimitExceeded);
         }
 
@@ -166,6 +469,7 @@ impl PyWriter {
             Some(Object::None) => self.w_kind(Kind::None, is_ref),
             Some(Object::StopIteration) => self.w_kind(Kind::StopIteration, is_ref),
             Some(Object::Ellipsis) => self.w_kind(Kind::Ellipsis, is_ref),
+            // Booleans are `T`/`F` in every marshal version, like CPython (they were never written as ints)
             Some(Object::Bool(value)) => {
                 self.w_kind(if value { Kind::True } else { Kind::False } , is_ref);
             }
@@ -179,13 +483,14 @@ impl PyWriter {
                     self.w_PyLong(num)?;
                 }
             }
+            // The binary float and complex kinds were added in marshal version 2, before that they are written as strings
             Some(Object::Float(value)) => {
                 if self.marshal_version > 1 {
                     self.w_kind(Kind::BinaryFloat, is_ref);
                     self.w_float_bin(value.into_inner());
                 } else {
                     self.w_kind(Kind::Float, is_ref);
-                    self.w_float_str(value.into_inner());
+                    self.w_float_str(value.into_inner())?;
                 }
             }
             Some(Object::Complex(Complex { re, im })) => {
@@ -195,47 +500,48 @@ impl PyWriter {
                     self.w_float_bin(im.into_inner());
                 } else {
                     self.w_kind(Kind::Complex, is_ref);
-                    self.w_float_str(re.into_inner());
-                    self.w_float_str(im.into_inner());
+                    self.w_float_str(re.into_inner())?;
+                    self.w_float_str(im.into_inner())?;
                 }
             }
             Some(Object::Bytes(value)) => {
                 self.w_kind(Kind::String, is_ref);
-                self.w_long(value.len() as i32);
+                self.w_size(value.len())?;
                 self.w_bytes(&value);
             }
             Some(Object::String(value)) => {
                 let str_value = &value.value;
 
-                match value.kind {
-                    Kind::ASCII | Kind::ASCIIInterned | Kind::Interned => {
-                        self.w_kind(value.kind, is_ref);
-                        self.w_long(str_value.len() as i32);
+                match string_kind(value.kind, self.marshal_version) {
+                    kind @ (Kind::ASCII | Kind::ASCIIInterned | Kind::Interned) => {
+                        self.w_kind(kind, is_ref);
+                        self.w_size(str_value.len())?;
                         self.w_bytes(&str_value.iter().copied().collect::<Vec<u8>>());
                     }
-                    Kind::ShortAscii | Kind::ShortAsciiInterned => {
-                        self.w_kind(value.kind, is_ref);
-                        self.w_u8(str_value.len() as u8);
+                    kind @ (Kind::ShortAscii | Kind::ShortAsciiInterned) => {
+                        self.w_kind(kind, is_ref);
+                        self.w_short_size(str_value.len())?;
                         self.w_bytes(&str_value.iter().copied().collect::<Vec<u8>>());
                     }
                     Kind::Unicode => {
                         self.w_kind(Kind::Unicode, is_ref);
-                        self.w_string(str_value, false);
+                        self.w_string(str_value, false)?;
                     }
                     _ => {
-                        panic!("Invalid string kind: {:?}", value.kind);
+                        return Err(Error::InvalidKind(value.kind));
                     }
                 }
             }
             Some(Object::Tuple(value)) => {
                 let size = value.len();
 
+                // SmallTuple was added in marshal version 4
                 if self.marshal_version >= 4 && size <= 255 {
                     self.w_kind(Kind::SmallTuple, is_ref);
-                    self.w_u8(size as u8);
+                    self.w_short_size(size)?;
                 } else {
                     self.w_kind(Kind::Tuple, is_ref);
-                    self.w_long(size as i32);
+                    self.w_size(size)?;
                 }
 
                 for item in value.iter() {
@@ -246,7 +552,7 @@ impl PyWriter {
                 let size = value.len();
 
                 self.w_kind(Kind::List, is_ref);
-                self.w_long(size as i32);
+                self.w_size(size)?;
 
                 for item in value.iter() {
                     self.w_object(Some(item.clone().clone()), false)?;
@@ -259,13 +565,13 @@ impl PyWriter {
                     self.w_object(Some((*value).clone()), false)?;
                 }
 
-                self.w_kind(Kind::Null, is_ref); // NULL object terminated
+                self.w_kind(Kind::Null, false); // NULL object terminated, the terminator itself is never a reference
             }
             Some(Object::Set(value)) => {
                 let size = value.len();
 
                 self.w_kind(Kind::Set, is_ref);
-                self.w_long(size as i32);
+                self.w_size(size)?;
 
                 for item in value.iter() {
                     self.w_object(Some((*item).clone().into()), false)?;
@@ -275,7 +581,7 @@ impl PyWriter {
                 let size = value.len();
 
                 self.w_kind(Kind::FrozenSet, is_ref);
-                self.w_long(size as i32);
+                self.w_size(size)?;
 
                 for item in value.iter() {
                     self.w_object(Some((*item).clone().into()), false)?;
@@ -285,6 +591,52 @@ impl PyWriter {
                 let value = value;
 
                 match value {
+                    Code::V30(value) => {
+                        // https://github.com/python/cpython/blob/3.7/Python/marshal.c
+                        self.w_kind(Kind::Code, is_ref);
+                        self.w_long(
+                            value
+                                .argcount
+                                .try_into()
+                                .map_err(|_| Error::InvalidConversion)?,
+                        );
+                        self.w_long(
+                            value
+                                .kwonlyargcount
+                                .try_into()
+                                .map_err(|_| Error::InvalidConversion)?,
+                        );
+                        self.w_long(
+                            value
+                                .nlocals
+                                .try_into()
+                                .map_err(|_| Error::InvalidConversion)?,
+                        );
+                        self.w_long(
+                            value
+                                .stacksize
+                                .try_into()
+                                .map_err(|_| Error::InvalidConversion)?,
+                        );
+                        self.w_long(
+                            value.flags.bits() as i32, // Stored as a C int, like the reader
+                        );
+                        self.w_object(Some((*value.code).clone()), false)?;
+                        self.w_object(Some((*value.consts).clone()), false)?;
+                        self.w_object(Some((*value.names).clone()), false)?;
+                        self.w_object(Some((*value.varnames).clone()), false)?;
+                        self.w_object(Some((*value.freevars).clone()), false)?;
+                        self.w_object(Some((*value.cellvars).clone()), false)?;
+                        self.w_object(Some((*value.filename).clone()), false)?;
+                        self.w_object(Some((*value.name).clone()), false)?;
+                        self.w_long(
+                            value
+                                .firstlineno
+                                .try_into()
+                                .map_err(|_| Error::InvalidConversion)?,
+                        );
+                        self.w_object(Some((*value.lnotab).clone()), false)?;
+                    }
                     Code::V310(value) => {
                         // https://github.com/python/cpython/blob/3.10/Python/marshal.c#L511
                         self.w_kind(Kind::Code, is_ref);
@@ -319,11 +671,7 @@ impl PyWriter {
                                 .map_err(|_| Error::InvalidConversion)?,
                         );
                         self.w_long(
-                            value
-                                .flags
-                                .bits()
-                                .try_into()
-                                .map_err(|_| Error::InvalidConversion)?,
+                            value.flags.bits() as i32, // Stored as a C int, like the reader
                         );
                         self.w_object(Some((*value.code).clone()), false)?;
                         self.w_object(Some((*value.consts).clone()), false)?;
@@ -357,14 +705,27 @@ impl PyWriter {
 
                 match reference {
                     None => {
-                        panic!("Reference {index} not found in references list");
+                        return Err(Error::InvalidReference(index));
+                    }
+                    // Marshal versions before 3 have no references, the object is written again at every use
+                    Some(reference) if self.marshal_version < 3 => {
+                        self.w_object(Some((*reference).clone()), false)?;
                     }
                     Some(_) => {
                         self.w_kind(Kind::Ref, is_ref);
-                        self.w_long(index as i32);
+                        // A reference that isn't stored by this object keeps its index, e.g. when writing a single field
+                        self.w_size(self.stored.get(&index).copied().unwrap_or(index))?;
                     }
                 }
             }
+            Some(Object::Raw { start, len }) => {
+                // The bytes of a raw object aren't available, it has to be decoded with `decode_raw` first
+                return Err(Error::InvalidObject(Object::Raw { start, len }));
+            }
+            Some(Object::Unknown { offset }) => {
+                // Only produced by a tolerant reader for data that couldn't be read, there is nothing to write
+                return Err(Error::InvalidObject(Object::Unknown { offset }));
+            }
             Some(Object::StoreRef(index)) => {
                 let reference = self.references.get(index);
 
@@ -372,7 +733,13 @@ impl PyWriter {
                     None => {
                         return Err(Error::InvalidReference(index));
                     }
+                    Some(reference) if self.marshal_version < 3 => {
+                        self.w_object(Some((*reference).clone()), false)?;
+                    }
                     Some(reference) => {
+                        // The reference table can have gaps, e.g. slots of dict keys that were dropped because their value was NULL
+                        self.stored.insert(index, self.flagged);
+                        self.flagged += 1;
                         self.w_object(Some((*reference).clone()), true)?;
                     }
                 }
@@ -384,9 +751,141 @@ impl PyWriter {
         Ok(())
     }
 
+    /// Writes the object. A root `StoreRef` is written with FLAG_REF, so it can be referenced by its own children.
+    /// Before marshal version 3 there are no references, every `LoadRef` and `StoreRef` is written as the referenced object
+    /// without FLAG_REF. A recursive reference can't be written inline and fails with `Error::DepthLimitExceeded`.
+    /// Returns a copy of everything written so far, use `finish` (or `into_bytes` after writing) to get the output without copying it.
     pub fn write_object(&mut self, obj: Option<Object>) -> Result<Vec<u8>, Error> {
         self.w_object(obj, false)?;
 
         Ok(self.data.clone())
     }
+
+    /// Checks that every `LoadRef` and `StoreRef` in the object, and in the references it stores, points into the reference table.
+    /// Returns `Error::InvalidReference` with the first index that is out of bounds, before anything is written.
+    pub fn validate_references(&self, obj: &Object) -> Result<(), Error> {
+        check_reference_indices(obj, &self.references)
+    }
+
+    /// Same as `write_object`, but consumes the writer so the written data is returned without copying it.
+    pub fn finish(mut self, obj: Object) -> Result<Vec<u8>, Error> {
+        self.w_object(Some(obj), false)?;
+
+        Ok(self.into_bytes())
+    }
+
+    /// Writes a single kind byte, with FLAG_REF set if `is_ref` is true.
+    /// This is a low-level API meant for hand-assembling (possibly invalid) marshal data, e.g. to test or fuzz a reader.
+    /// Nothing is validated, use `write_object` for regular serialization.
+    pub fn write_raw_kind(&mut self, kind: Kind, is_ref: bool) {
+        self.w_kind(kind, is_ref);
+    }
+
+    /// Writes a little-endian 32-bit integer. See `write_raw_kind`, this is a low-level API.
+    pub fn write_raw_long(&mut self, value: i32) {
+        self.w_long(value);
+    }
+
+    /// Writes the bytes as is, without a length prefix. See `write_raw_kind`, this is a low-level API.
+    pub fn write_raw_bytes(&mut self, value: &[u8]) {
+        self.w_bytes(value);
+    }
+
+    /// Returns everything written so far.
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Consumes the writer and returns the written data without copying it, the efficient way to finish writing.
+    /// Same as `into_inner`, `write_object` is only needed to keep writing with the same writer afterwards.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.into_inner()
+    }
+}
+
+impl Code {
+    /// Returns how many bytes each field of the code object takes up when serialized, in marshal order.
+    /// Every object field is written in isolation, so a `LoadRef` field only counts the reference itself (from marshal version 3).
+    pub fn serialized_field_sizes(
+        &self,
+        references: &[Object],
+        marshal_version: u8,
+    ) -> Result<Vec<(&'static str, usize)>, Error> {
+        let size = |obj: &Object| object_size(obj, references, marshal_version, 0);
+
+        Ok(match self {
+            Code::V30(value) => vec![
+                ("argcount", LONG_SIZE),
+                ("kwonlyargcount", LONG_SIZE),
+                ("nlocals", LONG_SIZE),
+                ("stacksize", LONG_SIZE),
+                ("flags", LONG_SIZE),
+                ("code", size(&value.code)?),
+                ("consts", size(&value.consts)?),
+                ("names", size(&value.names)?),
+                ("varnames", size(&value.varnames)?),
+                ("freevars", size(&value.freevars)?),
+                ("cellvars", size(&value.cellvars)?),
+                ("filename", size(&value.filename)?),
+                ("name", size(&value.name)?),
+                ("firstlineno", LONG_SIZE),
+                ("lnotab", size(&value.lnotab)?),
+            ],
+            Code::V310(value) => vec![
+                ("argcount", LONG_SIZE),
+                ("posonlyargcount", LONG_SIZE),
+                ("kwonlyargcount", LONG_SIZE),
+                ("nlocals", LONG_SIZE),
+                ("stacksize", LONG_SIZE),
+                ("flags", LONG_SIZE),
+                ("code", size(&value.code)?),
+                ("consts", size(&value.consts)?),
+                ("names", size(&value.names)?),
+                ("varnames", size(&value.varnames)?),
+                ("freevars", size(&value.freevars)?),
+                ("cellvars", size(&value.cellvars)?),
+                ("filename", size(&value.filename)?),
+                ("name", size(&value.name)?),
+                ("firstlineno", LONG_SIZE),
+                ("linetable", size(&value.linetable)?),
+            ],
+            Code::V311(value) => code311_field_sizes!(value, size),
+            Code::V312(value) => code311_field_sizes!(value, size),
+            Code::V313(value) => code311_field_sizes!(value, size),
+        })
+    }
+}
+
+impl Object {
+    /// Returns the kind the writer emits for this object in `marshal_version`, without FLAG_REF.
+    /// A `StoreRef` is written as the referenced object with FLAG_REF set, so `Kind::FlagRef` is returned for it, resolve the reference to get the actual kind.
+    /// Before marshal version 3 both kinds of references are written as the referenced object, so they have to be resolved too.
+    /// A `Raw` object can't be written, `Kind::Unknown` is returned for it.
+    pub fn marshal_kind(&self, marshal_version: u8) -> Kind {
+        match self {
+            Object::None => Kind::None,
+            Object::StopIteration => Kind::StopIteration,
+            Object::Ellipsis => Kind::Ellipsis,
+            Object::Bool(true) => Kind::True,
+            Object::Bool(false) => Kind::False,
+            Object::Long(num) if num.to_i32().is_some() => Kind::Int,
+            Object::Long(_) => Kind::Long,
+            Object::Float(_) if marshal_version > 1 => Kind::BinaryFloat,
+            Object::Float(_) => Kind::Float,
+            Object::Complex(_) if marshal_version > 1 => Kind::BinaryComplex,
+            Object::Complex(_) => Kind::Complex,
+            Object::Bytes(_) => Kind::String,
+            Object::String(value) => string_kind(value.kind, marshal_version),
+            Object::Tuple(value) if marshal_version >= 4 && value.len() <= 255 => Kind::SmallTuple,
+            Object::Tuple(_) => Kind::Tuple,
+            Object::List(_) => Kind::List,
+            Object::Dict(_) => Kind::Dict,
+            Object::Set(_) => Kind::Set,
+            Object::FrozenSet(_) => Kind::FrozenSet,
+            Object::Code(_) => Kind::Code,
+            Object::LoadRef(_) => Kind::Ref,
+            Object::StoreRef(_) => Kind::FlagRef,
+            Object::Raw { .. } | Object::Unknown { .. } => Kind::Unknown,
+        }
+    }
 }
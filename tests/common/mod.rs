@@ -97,8 +97,8 @@ fn compile_repo(version: &PyVersion) {
         };
         if result.is_err() {
             println!(
-                "Failed to compile standard library for Python version {}, still continuing",
-                format!("{}.{}", version.major, version.minor)
+                "Failed to compile standard library for Python version {}.{}, still continuing",
+                version.major, version.minor
             );
         }
     }
@@ -134,8 +134,8 @@ pub fn setup() {
                 Ok(_) => {}
                 Err(_) => {
                     println!(
-                        "Python version {} is not installed",
-                        format!("{}.{}", version.major, version.minor)
+                        "Python version {}.{} is not installed",
+                        version.major, version.minor
                     );
                     continue;
                 }
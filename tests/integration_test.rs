@@ -7,8 +7,8 @@ use std::{
 
 use num_traits::FromPrimitive;
 use python_marshal::{
-    Kind, PycFile, dump_bytes, magic::PyVersion, minimize_references, optimize_references,
-    resolver::resolve_all_refs,
+    Kind, PycFile, dump_bytes, dump_bytes_optimized, magic::PyVersion, minimize_references,
+    objects_equal, resolver::resolve_all_refs,
 };
 
 mod common;
@@ -47,20 +47,27 @@ fn test_recompile_standard_lib() {
             let code = python_marshal::load_pyc(&mut reader).expect("Failed to read pyc file");
             let original = std::fs::read(pyc_file).expect("Failed to read pyc file");
 
-            let (temp_obj, temp_refs) = optimize_references(&code.object, &code.references);
-
-            dump_bytes(temp_obj, Some(temp_refs), code.python_version, 4)
-                .expect("Failed to dump bytes");
+            dump_bytes_optimized(
+                code.object.clone(),
+                code.references.clone(),
+                code.python_version,
+                4,
+            )
+            .expect("Failed to dump bytes");
 
             let (temp_obj, temp_refs) = resolve_all_refs(&code.object, &code.references);
 
             assert_eq!(temp_refs.len(), 0);
+            assert!(objects_equal(
+                &code.object,
+                &code.references,
+                &temp_obj,
+                &temp_refs
+            ));
 
             dump_bytes(temp_obj, Some(temp_refs), code.python_version, 4)
                 .expect("Failed to dump bytes");
 
-            assert_eq!(code, code);
-
             let dumped = python_marshal::dump_pyc(code.clone()).expect("Failed to dump pyc file");
 
             if original != dumped {
@@ -70,42 +77,38 @@ fn test_recompile_standard_lib() {
 
                 const CONTEXT_SIZE: usize = 50;
 
-                diff_bytearrays(&original, &dumped)
-                    .iter()
-                    .for_each(|(i, a, b)| {
+                if let Some((i, a, b)) = diff_bytearrays(&original, &dumped).first() {
+                    println!(
+                        "bytearrays differ at index {}: {:?} ({:?}) != {:?} ({:?})",
+                        i,
+                        a,
+                        Kind::from_u8(a & !(Kind::FlagRef as u8)).unwrap_or(Kind::Unknown),
+                        b,
+                        Kind::from_u8(b & !(Kind::FlagRef as u8)).unwrap_or(Kind::Unknown)
+                    );
+
+                    let start = (*i).saturating_sub(CONTEXT_SIZE);
+                    let end = if *i + CONTEXT_SIZE < original.len() {
+                        *i + CONTEXT_SIZE
+                    } else {
+                        original.len() - 1
+                    };
+
+                    for j in start..=end {
                         println!(
-                            "bytearrays differ at index {}: {:?} ({:?}) != {:?} ({:?})",
-                            i,
-                            a,
-                            Kind::from_u8(a & !(Kind::FlagRef as u8))
+                            "index {}: original byte {:?} ({:?}), dumped byte {:?} ({:?})",
+                            j,
+                            original[j],
+                            Kind::from_u8(original[j] & !(Kind::FlagRef as u8))
                                 .unwrap_or(Kind::Unknown),
-                            b,
-                            Kind::from_u8(b & !(Kind::FlagRef as u8))
+                            dumped[j],
+                            Kind::from_u8(dumped[j] & !(Kind::FlagRef as u8))
                                 .unwrap_or(Kind::Unknown)
                         );
+                    }
 
-                        let start = (*i).saturating_sub(CONTEXT_SIZE);
-                        let end = if *i + CONTEXT_SIZE < original.len() {
-                            *i + CONTEXT_SIZE
-                        } else {
-                            original.len() - 1
-                        };
-
-                        for j in start..=end {
-                            println!(
-                                "index {}: original byte {:?} ({:?}), dumped byte {:?} ({:?})",
-                                j,
-                                original[j],
-                                Kind::from_u8(original[j] & !(Kind::FlagRef as u8))
-                                    .unwrap_or(Kind::Unknown),
-                                dumped[j],
-                                Kind::from_u8(dumped[j] & !(Kind::FlagRef as u8))
-                                    .unwrap_or(Kind::Unknown)
-                            );
-                        }
-
-                        assert!(false, "bytearrays differ at index {}", i);
-                    });
+                    panic!("bytearrays differ at index {}", i);
+                }
             }
         });
     });